@@ -1,21 +1,55 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupSet;
-use near_sdk::json_types::ValidAccountId;
-use near_sdk::{env, near_bindgen, AccountId, BorshStorageKey, PanicOnDefault};
+use near_sdk::collections::{LookupMap, LookupSet, UnorderedSet};
+use near_sdk::json_types::{ValidAccountId, WrappedBalance, WrappedTimestamp};
+use near_sdk::{
+    env, near_bindgen, AccountId, Balance, BorshStorageKey, PanicOnDefault, Promise, StorageUsage,
+    Timestamp,
+};
 
 near_sdk::setup_alloc!();
 
 #[derive(BorshStorageKey, BorshSerialize)]
 pub(crate) enum StorageKey {
     Accounts,
+    Allocations,
+    SaleApprovals,
+    Approvers,
+    ApprovedAccounts,
+    ApprovalExpirations,
+}
+
+#[derive(BorshDeserialize)]
+pub struct OldContract {
+    pub approved_accounts: LookupSet<AccountId>,
+
+    pub owner_id: AccountId,
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
-    pub approved_accounts: LookupSet<AccountId>,
+    /// Approved accounts not yet moved into `approved_accounts` by `migrate_approved_accounts`.
+    pub legacy_approved_accounts: LookupSet<AccountId>,
 
     pub owner_id: AccountId,
+
+    /// Allocations by account ID and sale ID. `None` sale ID is used for all sales.
+    pub allocations: LookupMap<(AccountId, Option<u64>), Balance>,
+
+    /// Accounts approved for a single sale.
+    pub sale_approvals: LookupSet<(AccountId, u64)>,
+
+    /// Accounts that can approve and reject accounts in addition to the owner.
+    pub approvers: UnorderedSet<AccountId>,
+
+    /// The account that can accept the ownership.
+    pub proposed_owner_id: Option<AccountId>,
+
+    /// Accounts approved for all sales.
+    pub approved_accounts: UnorderedSet<AccountId>,
+
+    /// Timestamps in nanoseconds after which the approvals for all sales are no longer valid.
+    pub approval_expirations: LookupMap<AccountId, Timestamp>,
 }
 
 #[near_bindgen]
@@ -23,8 +57,30 @@ impl Contract {
     #[init]
     pub fn new(owner_id: ValidAccountId) -> Self {
         Self {
-            approved_accounts: LookupSet::new(StorageKey::Accounts),
+            legacy_approved_accounts: LookupSet::new(StorageKey::Accounts),
             owner_id: owner_id.into(),
+            allocations: LookupMap::new(StorageKey::Allocations),
+            sale_approvals: LookupSet::new(StorageKey::SaleApprovals),
+            approvers: UnorderedSet::new(StorageKey::Approvers),
+            proposed_owner_id: None,
+            approved_accounts: UnorderedSet::new(StorageKey::ApprovedAccounts),
+            approval_expirations: LookupMap::new(StorageKey::ApprovalExpirations),
+        }
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old_contract: OldContract = env::state_read().expect("Not initialized");
+        Self {
+            legacy_approved_accounts: old_contract.approved_accounts,
+            owner_id: old_contract.owner_id,
+            allocations: LookupMap::new(StorageKey::Allocations),
+            sale_approvals: LookupSet::new(StorageKey::SaleApprovals),
+            approvers: UnorderedSet::new(StorageKey::Approvers),
+            proposed_owner_id: None,
+            approved_accounts: UnorderedSet::new(StorageKey::ApprovedAccounts),
+            approval_expirations: LookupMap::new(StorageKey::ApprovalExpirations),
         }
     }
 
@@ -32,24 +88,265 @@ impl Contract {
         true
     }
 
-    #[allow(unused_variables)]
     pub fn is_approved(&self, account_id: ValidAccountId, sale_id: u64) -> bool {
-        self.approved_accounts.contains(account_id.as_ref())
+        self.internal_is_approved(account_id.as_ref(), sale_id)
     }
 
-    pub fn approve(&mut self, account_id: ValidAccountId) {
-        self.assert_called_by_owner();
-        self.approved_accounts.insert(account_id.as_ref());
+    /// Returns the allocation of the account for the sale, or `None` if it's not approved.
+    pub fn get_allocation(
+        &self,
+        account_id: ValidAccountId,
+        sale_id: u64,
+    ) -> Option<WrappedBalance> {
+        let account_id: AccountId = account_id.into();
+        if !self.internal_is_approved(&account_id, sale_id) {
+            return None;
+        }
+        let allocation = self
+            .allocations
+            .get(&(account_id.clone(), Some(sale_id)))
+            .or_else(|| self.allocations.get(&(account_id, None)))
+            .unwrap_or(Balance::MAX);
+        Some(allocation.into())
+    }
+
+    /// Approves the account for all sales, until the given timestamp in nanoseconds if given.
+    #[payable]
+    pub fn approve(&mut self, account_id: ValidAccountId, expires_at: Option<WrappedTimestamp>) {
+        self.assert_called_by_approver();
+        let initial_storage_usage = env::storage_usage();
+        self.internal_approve(account_id.as_ref(), expires_at.map(|t| t.into()));
+        refund_extra_storage_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
     }
 
     pub fn reject(&mut self, account_id: ValidAccountId) {
+        self.assert_called_by_approver();
+        self.internal_reject(account_id.as_ref());
+    }
+
+    /// Approves the given accounts for all sales.
+    #[payable]
+    pub fn approve_many(
+        &mut self,
+        account_ids: Vec<ValidAccountId>,
+        expires_at: Option<WrappedTimestamp>,
+    ) {
+        self.assert_called_by_approver();
+        let initial_storage_usage = env::storage_usage();
+        let expires_at = expires_at.map(|t| t.into());
+        for account_id in account_ids {
+            self.internal_approve(account_id.as_ref(), expires_at);
+        }
+        refund_extra_storage_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    pub fn reject_many(&mut self, account_ids: Vec<ValidAccountId>) {
+        self.assert_called_by_approver();
+        for account_id in account_ids {
+            self.internal_reject(account_id.as_ref());
+        }
+    }
+
+    /// Moves accounts approved before the approvals became enumerable into the enumerable set.
+    pub fn migrate_approved_accounts(&mut self, account_ids: Vec<ValidAccountId>) {
+        self.assert_called_by_owner();
+        for account_id in account_ids {
+            if self.legacy_approved_accounts.remove(account_id.as_ref()) {
+                self.approved_accounts.insert(account_id.as_ref());
+            }
+        }
+    }
+
+    pub fn get_approved_accounts(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<AccountId> {
+        let keys = self.approved_accounts.as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(keys.len());
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .map(|index| keys.get(index).unwrap())
+            .collect()
+    }
+
+    /// Returns accounts approved for all sales with the approval expiring before the timestamp.
+    pub fn get_expiring_approvals(
+        &self,
+        before: WrappedTimestamp,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, WrappedTimestamp)> {
+        let before: Timestamp = before.into();
+        let keys = self.approved_accounts.as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(keys.len());
+        (from_index..std::cmp::min(from_index + limit, keys.len()))
+            .filter_map(|index| {
+                let account_id = keys.get(index).unwrap();
+                self.approval_expirations
+                    .get(&account_id)
+                    .filter(|expires_at| *expires_at < before)
+                    .map(|expires_at| (account_id, expires_at.into()))
+            })
+            .collect()
+    }
+
+    pub fn get_approval_expiration(&self, account_id: ValidAccountId) -> Option<WrappedTimestamp> {
+        self.approval_expirations
+            .get(account_id.as_ref())
+            .map(|expires_at| expires_at.into())
+    }
+
+    /// Returns the number of enumerable accounts approved for all sales.
+    pub fn get_num_approved(&self) -> u64 {
+        self.approved_accounts.len()
+    }
+
+    /// Approves the account for the given sale.
+    #[payable]
+    pub fn approve_for_sale(&mut self, account_id: ValidAccountId, sale_id: u64) {
+        self.assert_called_by_owner();
+        let initial_storage_usage = env::storage_usage();
+        self.sale_approvals.insert(&(account_id.into(), sale_id));
+        refund_extra_storage_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    pub fn reject_for_sale(&mut self, account_id: ValidAccountId, sale_id: u64) {
         self.assert_called_by_owner();
-        self.approved_accounts.remove(account_id.as_ref());
+        self.sale_approvals.remove(&(account_id.into(), sale_id));
+    }
+
+    /// Approves the given accounts for the given sale.
+    #[payable]
+    pub fn approve_many_for_sale(&mut self, account_ids: Vec<ValidAccountId>, sale_id: u64) {
+        self.assert_called_by_owner();
+        let initial_storage_usage = env::storage_usage();
+        for account_id in account_ids {
+            self.sale_approvals.insert(&(account_id.into(), sale_id));
+        }
+        refund_extra_storage_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
+    }
+
+    pub fn reject_many_for_sale(&mut self, account_ids: Vec<ValidAccountId>, sale_id: u64) {
+        self.assert_called_by_owner();
+        for account_id in account_ids {
+            self.sale_approvals.remove(&(account_id.into(), sale_id));
+        }
+    }
+
+    pub fn add_approver(&mut self, account_id: ValidAccountId) {
+        self.assert_called_by_owner();
+        self.approvers.insert(account_id.as_ref());
+    }
+
+    pub fn remove_approver(&mut self, account_id: ValidAccountId) {
+        self.assert_called_by_owner();
+        self.approvers.remove(account_id.as_ref());
+    }
+
+    /// Proposes the new owner, or cancels the proposal if no account ID is given.
+    pub fn propose_owner(&mut self, owner_id: Option<ValidAccountId>) {
+        self.assert_called_by_owner();
+        self.proposed_owner_id = owner_id.map(|a| a.into());
+    }
+
+    pub fn accept_ownership(&mut self) {
+        let account_id = env::predecessor_account_id();
+        assert_eq!(
+            self.proposed_owner_id.as_ref(),
+            Some(&account_id),
+            "Not the proposed owner"
+        );
+        self.owner_id = account_id;
+        self.proposed_owner_id = None;
+    }
+
+    pub fn get_owner_id(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_proposed_owner_id(&self) -> Option<AccountId> {
+        self.proposed_owner_id.clone()
+    }
+
+    pub fn get_approvers(&self) -> Vec<AccountId> {
+        self.approvers.to_vec()
+    }
+
+    /// Sets or removes the allocation of the account for the given sale, or for all sales.
+    #[payable]
+    pub fn set_allocation(
+        &mut self,
+        account_id: ValidAccountId,
+        sale_id: Option<u64>,
+        allocation: Option<WrappedBalance>,
+    ) {
+        self.assert_called_by_owner();
+        let initial_storage_usage = env::storage_usage();
+        let key = (account_id.into(), sale_id);
+        if let Some(allocation) = allocation {
+            self.allocations.insert(&key, &allocation.into());
+        } else {
+            self.allocations.remove(&key);
+        }
+        refund_extra_storage_deposit(env::storage_usage().saturating_sub(initial_storage_usage));
     }
 }
 
 impl Contract {
+    fn internal_is_approved(&self, account_id: &AccountId, sale_id: u64) -> bool {
+        (self.approved_accounts.contains(account_id)
+            && self
+                .approval_expirations
+                .get(account_id)
+                .map(|expires_at| env::block_timestamp() < expires_at)
+                .unwrap_or(true))
+            || self.legacy_approved_accounts.contains(account_id)
+            || self.sale_approvals.contains(&(account_id.clone(), sale_id))
+    }
+
+    fn internal_approve(&mut self, account_id: &AccountId, expires_at: Option<Timestamp>) {
+        self.legacy_approved_accounts.remove(account_id);
+        self.approved_accounts.insert(account_id);
+        if let Some(expires_at) = expires_at {
+            self.approval_expirations.insert(account_id, &expires_at);
+        } else {
+            self.approval_expirations.remove(account_id);
+        }
+    }
+
+    fn internal_reject(&mut self, account_id: &AccountId) {
+        self.approved_accounts.remove(account_id);
+        self.approval_expirations.remove(account_id);
+        self.legacy_approved_accounts.remove(account_id);
+    }
+
     fn assert_called_by_owner(&self) {
         assert_eq!(&self.owner_id, &env::predecessor_account_id());
     }
+
+    fn assert_called_by_approver(&self) {
+        let account_id = env::predecessor_account_id();
+        assert!(
+            self.owner_id == account_id || self.approvers.contains(&account_id),
+            "Not an approver"
+        );
+    }
+}
+
+fn refund_extra_storage_deposit(storage_used: StorageUsage) {
+    let required_cost = env::storage_byte_cost() * Balance::from(storage_used);
+    let attached_deposit = env::attached_deposit();
+
+    assert!(
+        required_cost <= attached_deposit,
+        "Not enough attached deposit to cover storage {}",
+        required_cost,
+    );
+
+    let refund = attached_deposit - required_cost;
+    if refund > 1 {
+        Promise::new(env::predecessor_account_id()).transfer(refund);
+    }
 }
@@ -1,20 +1,38 @@
 use crate::*;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 use near_sdk::collections::{UnorderedMap, UnorderedSet};
 use near_sdk::json_types::{WrappedBalance, U128};
-use near_sdk::{assert_one_yocto, serde_json, PromiseOrValue};
+use near_sdk::serde_json::{self, json};
+use near_sdk::{assert_one_yocto, PromiseOrValue};
 
 const REFERRAL_FEE_DENOMINATOR: u128 = 10000;
 
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct OldAccount {
+    pub balances: UnorderedMap<TokenAccountId, Balance>,
+    pub subs: UnorderedMap<u64, VSubscription>,
+    pub sales: UnorderedSet<u64>,
+}
+
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct Account {
     pub balances: UnorderedMap<TokenAccountId, Balance>,
     pub subs: UnorderedMap<u64, VSubscription>,
     pub sales: UnorderedSet<u64>,
+    /// NEAR deposited with `storage_deposit` that is not used for storage yet.
+    pub storage_balance: Balance,
+    /// NEAR deposited with `storage_deposit`, less the withdrawn amount.
+    pub storage_deposited: Balance,
+    /// Accounts that can deposit, withdraw and claim in sales on behalf of this account.
+    pub operators: Vec<Operator>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum VAccount {
+    First(OldAccount),
     Current(Account),
 }
 
@@ -27,6 +45,14 @@ impl From<Account> for VAccount {
 impl From<VAccount> for Account {
     fn from(v_account: VAccount) -> Self {
         match v_account {
+            VAccount::First(old_account) => Account {
+                balances: old_account.balances,
+                subs: old_account.subs,
+                sales: old_account.sales,
+                storage_balance: 0,
+                storage_deposited: 0,
+                operators: vec![],
+            },
             VAccount::Current(account) => account,
         }
     }
@@ -75,17 +101,11 @@ impl Account {
         (subscription, out_token_amounts)
     }
 
-    pub fn internal_save_subscription(
-        &mut self,
-        sale_id: u64,
-        sale: &Sale,
-        subscription: Subscription,
-    ) {
-        if subscription.shares == 0 && (sale.permissions_contract_id.is_none() || sale.has_ended())
-        {
-            self.subs.remove(&sale_id);
-        } else {
-            self.subs.insert(&sale_id, &subscription.into());
+    /// The total is the NEAR held from `storage_deposit`, and the available part is not used yet.
+    pub fn storage_balance_output(&self) -> StorageBalance {
+        StorageBalance {
+            total: self.storage_deposited.into(),
+            available: self.storage_balance.into(),
         }
     }
 
@@ -96,13 +116,30 @@ impl Account {
     ) -> Option<SubscriptionOutput> {
         let (subscription, out_token_remaining) =
             self.internal_get_subscription(sale_id, sale, None, true);
-        if subscription.shares > 0 || out_token_remaining.iter().any(|&v| v > 0) {
-            let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
+        let is_failed = sale.is_failed();
+        let out_token_remaining: Vec<Balance> = out_token_remaining
+            .into_iter()
+            .zip(subscription.locked_out_balance.iter())
+            .map(
+                |(amount, locked_amount)| {
+                    if is_failed {
+                        0
+                    } else {
+                        amount + locked_amount
+                    }
+                },
+            )
+            .collect();
+        let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
+        let spent_in_balance = subscription.spent_in_balance_without_shares
+            + (subscription.last_in_balance - remaining_in_balance);
+        if subscription.shares > 0
+            || out_token_remaining.iter().any(|&v| v > 0)
+            || (is_failed && spent_in_balance > 0)
+        {
             Some(SubscriptionOutput {
                 remaining_in_balance: remaining_in_balance.into(),
-                spent_in_balance: (subscription.spent_in_balance_without_shares
-                    + (subscription.last_in_balance - remaining_in_balance))
-                    .into(),
+                spent_in_balance: spent_in_balance.into(),
                 unclaimed_out_balances: out_token_remaining.into_iter().map(|b| b.into()).collect(),
                 claimed_out_balance: subscription
                     .claimed_out_balance
@@ -111,6 +148,7 @@ impl Account {
                     .collect(),
                 shares: subscription.shares.into(),
                 referral_id: subscription.referral_id,
+                max_price: subscription.max_price.map(|p| p.into()),
             })
         } else {
             None
@@ -119,6 +157,23 @@ impl Account {
 }
 
 impl Contract {
+    pub fn internal_new_account(account_id: &AccountId) -> Account {
+        Account {
+            balances: UnorderedMap::new(StorageKey::AccountTokens {
+                account_id: account_id.clone(),
+            }),
+            subs: UnorderedMap::new(StorageKey::AccountSubs {
+                account_id: account_id.clone(),
+            }),
+            sales: UnorderedSet::new(StorageKey::AccountSales {
+                account_id: account_id.clone(),
+            }),
+            storage_balance: 0,
+            storage_deposited: 0,
+            operators: vec![],
+        }
+    }
+
     pub fn internal_unwrap_account(&self, account_id: &AccountId) -> Account {
         self.accounts
             .get(account_id)
@@ -126,6 +181,180 @@ impl Contract {
             .into()
     }
 
+    /// Returns the balance of the token in the account.
+    pub fn internal_token_balance(
+        &self,
+        account_id: &AccountId,
+        token_account_id: &TokenAccountId,
+    ) -> Balance {
+        self.internal_unwrap_account(account_id)
+            .balances
+            .get(token_account_id)
+            .unwrap_or(0)
+    }
+
+    /// Pays for the used storage with the deposit, then the storage balance, and refunds the rest.
+    pub fn internal_pay_storage(
+        &mut self,
+        payer_id: &AccountId,
+        storage_used: StorageUsage,
+        deposit: Balance,
+    ) {
+        let required_cost = env::storage_byte_cost() * Balance::from(storage_used);
+        if required_cost <= deposit {
+            let refund = deposit - required_cost;
+            if refund > 1 {
+                Promise::new(payer_id.clone()).transfer(refund);
+            }
+        } else {
+            let mut account: Account = self
+                .accounts
+                .get(payer_id)
+                .unwrap_or_else(|| {
+                    env::panic(
+                        format!("{} {}", errors::NOT_ENOUGH_ATTACHED_BALANCE, required_cost)
+                            .as_bytes(),
+                    )
+                })
+                .into();
+            self.internal_storage_withdraw(&mut account, required_cost - deposit);
+            self.accounts.insert(payer_id, &account.into());
+        }
+    }
+
+    pub fn internal_storage_deposit(&mut self, account: &mut Account, amount: Balance) {
+        account.storage_balance += amount;
+        self.treasury.storage_balances += amount;
+    }
+
+    pub fn internal_storage_withdraw(&mut self, account: &mut Account, amount: Balance) {
+        account.storage_balance = account
+            .storage_balance
+            .checked_sub(amount)
+            .expect(errors::NOT_ENOUGH_STORAGE_BALANCE);
+        self.treasury.storage_balances -= amount;
+    }
+
+    /// Returns the tokens the subscriptions and unfinished sales of the account can still deposit.
+    pub fn internal_tokens_in_use(&mut self, account_id: &AccountId) -> Vec<TokenAccountId> {
+        let account = self.internal_unwrap_account(account_id);
+        let owned_sale_ids = account.sales.to_vec();
+        let subscribed_sale_ids = account.subs.keys_as_vector().to_vec();
+        let mut tokens_in_use = vec![];
+        let mut add_sale_tokens = |sale: &Sale| {
+            for token_account_id in sale
+                .out_tokens
+                .iter()
+                .map(|out_token| &out_token.token_account_id)
+                .chain(std::iter::once(&sale.in_token_account_id))
+            {
+                if !tokens_in_use.contains(token_account_id) {
+                    tokens_in_use.push(token_account_id.clone());
+                }
+            }
+        };
+        for sale_id in owned_sale_ids {
+            let mut sale = self.internal_unwrap_sale(sale_id);
+            self.internal_distribute_unclaimed_tokens(&mut sale);
+            if !sale.cancelled && !sale.has_ended() {
+                add_sale_tokens(&sale);
+            }
+            self.sales.insert(&sale_id, &sale.into());
+        }
+        for sale_id in subscribed_sale_ids {
+            add_sale_tokens(&self.internal_unwrap_sale(sale_id));
+        }
+        tokens_in_use
+    }
+
+    /// Removes the empty account and refunds its storage balance with the released storage.
+    pub fn internal_close_account(&mut self, account_id: &AccountId) {
+        let initial_storage_usage = env::storage_usage();
+        let account = self.internal_unwrap_account(account_id);
+        assert!(
+            account.subs.is_empty(),
+            "{}",
+            errors::ACCOUNT_HAS_SUBSCRIPTIONS
+        );
+        assert!(
+            self.internal_tokens_in_use(account_id).is_empty(),
+            "{}",
+            errors::ACCOUNT_HAS_ACTIVE_SALES
+        );
+        let mut account = self.internal_unwrap_account(account_id);
+        assert!(
+            account.balances.values().all(|balance| balance == 0),
+            "{}",
+            errors::NON_ZERO_BALANCE
+        );
+        account.balances.clear();
+        account.subs.clear();
+        account.sales.clear();
+        let storage_balance = account.storage_balance;
+        self.internal_storage_withdraw(&mut account, storage_balance);
+        self.accounts.remove(account_id);
+        let refund = storage_balance
+            + env::storage_byte_cost()
+                * Balance::from(initial_storage_usage.saturating_sub(env::storage_usage()))
+            + env::attached_deposit();
+        Promise::new(account_id.clone()).transfer(refund);
+    }
+
+    /// Withdraws the given amount, or the whole balance, of the token from the account.
+    pub fn internal_withdraw_token_amount(
+        &mut self,
+        account_id: &AccountId,
+        token_account_id: &TokenAccountId,
+        amount: Option<WrappedBalance>,
+    ) -> Balance {
+        let mut account = self.internal_unwrap_account(account_id);
+        let amount = amount.map(|a| a.0).unwrap_or_else(|| {
+            account
+                .balances
+                .get(token_account_id)
+                .expect(errors::TOKEN_NOT_REGISTERED)
+        });
+        account.internal_token_withdraw(token_account_id, amount);
+        amount
+    }
+
+    /// Saves the subscription of the account, or removes it if it's no longer needed.
+    pub fn internal_save_subscription(
+        &mut self,
+        account_id: &AccountId,
+        account: &mut Account,
+        sale_id: u64,
+        sale: &Sale,
+        mut subscription: Subscription,
+    ) {
+        if subscription.is_removable(sale) {
+            self.internal_remove_subscription(account, sale_id, &subscription);
+        } else {
+            let token_id = subscription.token_id.unwrap_or_else(|| {
+                let token_id = self.next_subscription_token_id;
+                self.next_subscription_token_id += 1;
+                token_id
+            });
+            subscription.token_id = Some(token_id);
+            self.subscription_tokens
+                .insert(&token_id, &(sale_id, account_id.clone()));
+            account.subs.insert(&sale_id, &subscription.into());
+        }
+    }
+
+    /// Removes the subscription of the account together with its token.
+    pub fn internal_remove_subscription(
+        &mut self,
+        account: &mut Account,
+        sale_id: u64,
+        subscription: &Subscription,
+    ) {
+        if let Some(token_id) = subscription.token_id {
+            self.subscription_tokens.remove(&token_id);
+        }
+        account.subs.remove(&sale_id);
+    }
+
     pub fn internal_maybe_register_token(
         &mut self,
         account: &mut Account,
@@ -147,9 +376,40 @@ impl Contract {
         referral_id: Option<&AccountId>,
         passed_permission_check: bool,
     ) -> Subscription {
-        let create_new = passed_permission_check || sale.permissions_contract_id.is_none();
-        let (mut subscription, out_token_amounts) =
+        let create_new = passed_permission_check || !sale.is_gated();
+        let (mut subscription, mut out_token_amounts) =
             account.internal_get_subscription(sale_id, &sale, referral_id, create_new);
+        if sale.min_in_token_paid.is_some() {
+            let has_ended = sale.has_ended();
+            let is_failed = sale.is_failed();
+            for (amount, locked_amount) in out_token_amounts
+                .iter_mut()
+                .zip(subscription.locked_out_balance.iter_mut())
+            {
+                *locked_amount += *amount;
+                *amount = 0;
+                if has_ended {
+                    let locked_amount = std::mem::take(locked_amount);
+                    if !is_failed {
+                        *amount = locked_amount;
+                    }
+                }
+            }
+            if is_failed {
+                // Returning spent in tokens, since the out tokens went back to the sale owner.
+                let refund = std::cmp::min(
+                    subscription.spent_in_balance_without_shares + subscription.last_in_balance
+                        - sale.shares_to_in_balance(subscription.shares),
+                    sale.in_token_paid_unclaimed,
+                );
+                if refund > 0 {
+                    account.internal_token_deposit(&sale.in_token_account_id, refund);
+                    sale.in_token_paid_unclaimed -= refund;
+                }
+                subscription.spent_in_balance_without_shares = 0;
+                subscription.last_in_balance = sale.shares_to_in_balance(subscription.shares);
+            }
+        }
         for (index, (mut amount, out_token)) in out_token_amounts
             .into_iter()
             .zip(sale.out_tokens.iter())
@@ -228,22 +488,51 @@ impl Contract {
             .accounts
             .get(&account_id)
             .map(|a| a.into())
-            .unwrap_or_else(|| Account {
-                balances: UnorderedMap::new(StorageKey::AccountTokens {
-                    account_id: account_id.clone(),
-                }),
-                subs: UnorderedMap::new(StorageKey::AccountSubs {
-                    account_id: account_id.clone(),
-                }),
-                sales: UnorderedSet::new(StorageKey::AccountSales {
-                    account_id: account_id.clone(),
-                }),
-            });
+            .unwrap_or_else(|| Self::internal_new_account(&account_id));
         for token_account_id in token_account_ids {
             self.internal_maybe_register_token(&mut account, token_account_id.as_ref());
         }
         self.accounts.insert(&account_id, &account.into());
-        refund_extra_storage_deposit(env::storage_usage() - initial_storage_usage, 0);
+        self.internal_pay_storage(
+            &env::predecessor_account_id(),
+            env::storage_usage() - initial_storage_usage,
+            env::attached_deposit(),
+        );
+    }
+
+    /// Unregisters the tokens with zero balances and refunds the released storage.
+    #[payable]
+    pub fn unregister_tokens(&mut self, token_account_ids: Vec<ValidAccountId>) {
+        assert_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        let tokens_in_use = self.internal_tokens_in_use(&account_id);
+        let mut account = self.internal_unwrap_account(&account_id);
+        for token_account_id in token_account_ids {
+            let token_account_id: TokenAccountId = token_account_id.into();
+            assert!(
+                !tokens_in_use.contains(&token_account_id),
+                "{}",
+                errors::TOKEN_IN_USE
+            );
+            let balance = account
+                .balances
+                .remove(&token_account_id)
+                .expect(errors::TOKEN_NOT_REGISTERED);
+            assert_eq!(balance, 0, "{}", errors::NON_ZERO_BALANCE);
+        }
+        self.accounts.insert(&account_id, &account.into());
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    /// Closes the empty account and refunds all NEAR paid for its storage.
+    #[payable]
+    pub fn close_account(&mut self) {
+        assert_one_yocto();
+        self.internal_close_account(&env::predecessor_account_id());
     }
 
     pub fn withdraw_token(
@@ -252,15 +541,139 @@ impl Contract {
         amount: Option<WrappedBalance>,
     ) -> Promise {
         let account_id = env::predecessor_account_id();
+        let amount =
+            self.internal_withdraw_token_amount(&account_id, token_account_id.as_ref(), amount);
+        self.internal_ft_transfer(&account_id, token_account_id.as_ref(), amount)
+    }
+
+    /// Withdraws the tokens to the given receiver.
+    #[payable]
+    pub fn withdraw_token_to(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_account_id: ValidAccountId,
+        amount: Option<WrappedBalance>,
+    ) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let amount =
+            self.internal_withdraw_token_amount(&account_id, token_account_id.as_ref(), amount);
+        self.internal_ft_transfer_to(
+            &account_id,
+            receiver_id.as_ref(),
+            token_account_id.as_ref(),
+            amount,
+        )
+    }
+
+    /// Withdraws the tokens to the given receiver with `ft_transfer_call`.
+    #[payable]
+    pub fn withdraw_token_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_account_id: ValidAccountId,
+        amount: Option<WrappedBalance>,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let amount =
+            self.internal_withdraw_token_amount(&account_id, token_account_id.as_ref(), amount);
+        self.internal_ft_transfer_call(
+            &account_id,
+            receiver_id.as_ref(),
+            token_account_id.as_ref(),
+            amount,
+            msg,
+        )
+    }
+
+    /// Unwraps wNEAR from the account balance and sends it as NEAR.
+    pub fn withdraw_near(&mut self, amount: Option<WrappedBalance>) -> Promise {
+        let account_id = env::predecessor_account_id();
+        let w_near_token_id = self.treasury.w_near_token_id.clone();
         let mut account = self.internal_unwrap_account(&account_id);
         let amount = amount.map(|a| a.0).unwrap_or_else(|| {
             account
                 .balances
-                .get(token_account_id.as_ref())
+                .get(&w_near_token_id)
                 .expect(errors::TOKEN_NOT_REGISTERED)
         });
-        account.internal_token_withdraw(token_account_id.as_ref(), amount);
-        self.internal_ft_transfer(&account_id, token_account_id.as_ref(), amount)
+        account.internal_token_withdraw(&w_near_token_id, amount);
+        Promise::new(w_near_token_id)
+            .function_call(
+                b"near_withdraw".to_vec(),
+                json!({ "amount": WrappedBalance::from(amount) })
+                    .to_string()
+                    .into_bytes(),
+                ONE_YOCTO,
+                NEAR_WITHDRAW_GAS,
+            )
+            .then(ext_self::after_near_withdraw(
+                account_id,
+                amount.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                AFTER_NEAR_WITHDRAW_GAS,
+            ))
+    }
+
+    /// Transfers the tokens from the account balance to the receiver's balance.
+    #[payable]
+    pub fn transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_account_id: ValidAccountId,
+        amount: WrappedBalance,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        self.internal_transfer(
+            &env::predecessor_account_id(),
+            receiver_id.as_ref(),
+            token_account_id.as_ref(),
+            amount.0,
+            memo,
+        );
+    }
+
+    /// Transfers the tokens to the receiver's balance and calls `on_skyward_transfer` on it.
+    #[payable]
+    pub fn transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_account_id: ValidAccountId,
+        amount: WrappedBalance,
+        memo: Option<String>,
+        msg: String,
+    ) -> Promise {
+        assert_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer(
+            &sender_id,
+            receiver_id.as_ref(),
+            token_account_id.as_ref(),
+            amount.0,
+            memo,
+        );
+        ext_skyward_receiver::on_skyward_transfer(
+            sender_id.clone(),
+            token_account_id.clone().into(),
+            amount,
+            msg,
+            receiver_id.as_ref(),
+            NO_DEPOSIT,
+            ON_SKYWARD_TRANSFER_GAS,
+        )
+        .then(ext_self::after_transfer_call(
+            sender_id,
+            receiver_id.into(),
+            token_account_id.into(),
+            amount,
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            AFTER_TRANSFER_CALL_GAS,
+        ))
     }
 
     #[payable]
@@ -366,6 +779,95 @@ impl Contract {
     }
 }
 
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Registers the account, if needed, and adds the rest of the deposit to its storage balance.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<ValidAccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let mut amount = env::attached_deposit();
+        let account_id = account_id
+            .map(|a| a.into())
+            .unwrap_or_else(env::predecessor_account_id);
+        let mut account = if let Some(account) = self.accounts.get(&account_id) {
+            account.into()
+        } else {
+            let min_balance = self.storage_balance_bounds().min.0;
+            assert!(
+                amount >= min_balance,
+                "{} {}",
+                errors::NOT_ENOUGH_ATTACHED_BALANCE,
+                min_balance
+            );
+            let initial_storage_usage = env::storage_usage();
+            let account = Self::internal_new_account(&account_id);
+            self.accounts.insert(&account_id, &account.into());
+            amount -= env::storage_byte_cost()
+                * Balance::from(env::storage_usage() - initial_storage_usage);
+            self.internal_unwrap_account(&account_id)
+        };
+        if registration_only.unwrap_or(false) {
+            if amount > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(amount);
+            }
+            account.storage_deposited += env::attached_deposit() - amount;
+        } else {
+            self.internal_storage_deposit(&mut account, amount);
+            account.storage_deposited += env::attached_deposit();
+        }
+        let storage_balance = account.storage_balance_output();
+        self.accounts.insert(&account_id, &account.into());
+        storage_balance
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_unwrap_account(&account_id);
+        let amount = amount.map(|a| a.0).unwrap_or(account.storage_balance);
+        self.internal_storage_withdraw(&mut account, amount);
+        account.storage_deposited -= amount;
+        let storage_balance = account.storage_balance_output();
+        self.accounts.insert(&account_id, &account.into());
+        if amount > 0 {
+            Promise::new(account_id).transfer(amount);
+        }
+        storage_balance
+    }
+
+    /// Closes the account with `close_account`. Force unregistering is not supported.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        assert!(!force.unwrap_or(false), "{}", errors::FORCE_UNREGISTER);
+        let account_id = env::predecessor_account_id();
+        if self.accounts.get(&account_id).is_some() {
+            self.internal_close_account(&account_id);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: ACCOUNT_STORAGE_DEPOSIT.into(),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+        self.accounts.get(account_id.as_ref()).map(|account| {
+            let account: Account = account.into();
+            account.storage_balance_output()
+        })
+    }
+}
+
 #[near_bindgen]
 impl FungibleTokenReceiver for Contract {
     fn ft_on_transfer(
@@ -378,6 +880,27 @@ impl FungibleTokenReceiver for Contract {
             serde_json::from_str(&msg).expect(errors::FAILED_TO_PARSE_FT_ON_TRANSFER_MSG);
         let token_account_id = env::predecessor_account_id();
         match args {
+            FtOnTransferArgs::SaleDeposit {
+                sale_id,
+                referral_id,
+            } => {
+                return self.internal_sale_deposit_on_transfer(
+                    sale_id,
+                    sender_id.into(),
+                    token_account_id,
+                    amount.0,
+                    referral_id.map(|r| r.into()),
+                );
+            }
+            FtOnTransferArgs::SaleCreate { sale } => {
+                let sale_id = self.internal_sale_create_on_transfer(
+                    sender_id.into(),
+                    &token_account_id,
+                    amount.0,
+                    *sale,
+                );
+                log!("Created sale {}", sale_id);
+            }
             FtOnTransferArgs::AccountDeposit => {
                 let mut account = self.internal_unwrap_account(sender_id.as_ref());
                 account.internal_token_deposit(&token_account_id, amount.0);
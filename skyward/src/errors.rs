@@ -31,3 +31,44 @@ pub(crate) const TOO_LONG_URL: &str = "ERR_TOO_LONG_URL";
 pub(crate) const NO_PERMISSION: &str = "ERR_NO_PERMISSION";
 pub(crate) const NOT_APPROVED: &str = "ERR_NOT_APPROVED";
 pub(crate) const MAX_REFERRAL_BPT: &str = "ERR_MAX_REFERRAL_BPT";
+pub(crate) const NOT_SALE_OWNER: &str = "ERR_NOT_SALE_OWNER";
+pub(crate) const SALE_CANCELLED: &str = "ERR_SALE_CANCELLED";
+pub(crate) const SALE_STARTED: &str = "ERR_SALE_STARTED";
+pub(crate) const CANNOT_CANCEL_SKYWARD_SALE: &str = "ERR_CANNOT_CANCEL_SKYWARD_SALE";
+pub(crate) const INVALID_MIN_PRICE: &str = "ERR_INVALID_MIN_PRICE";
+pub(crate) const INVALID_MAX_IN_TOKEN: &str = "ERR_INVALID_MAX_IN_TOKEN";
+pub(crate) const MAX_IN_TOKEN_REACHED: &str = "ERR_MAX_IN_TOKEN_REACHED";
+pub(crate) const INVALID_IN_PER_ACCOUNT_LIMITS: &str = "ERR_INVALID_IN_PER_ACCOUNT_LIMITS";
+pub(crate) const MAX_IN_PER_ACCOUNT_REACHED: &str = "ERR_MAX_IN_PER_ACCOUNT_REACHED";
+pub(crate) const BELOW_MIN_IN_PER_ACCOUNT: &str = "ERR_BELOW_MIN_IN_PER_ACCOUNT";
+pub(crate) const MULTIPLE_SALE_GATES: &str = "ERR_MULTIPLE_SALE_GATES";
+pub(crate) const NO_WHITELIST: &str = "ERR_NO_WHITELIST";
+pub(crate) const INVALID_WHITELIST_PROOF: &str = "ERR_INVALID_WHITELIST_PROOF";
+pub(crate) const INVALID_VOUCHER_PUBLIC_KEY: &str = "ERR_INVALID_VOUCHER_PUBLIC_KEY";
+pub(crate) const NO_VOUCHER_PUBLIC_KEY: &str = "ERR_NO_VOUCHER_PUBLIC_KEY";
+pub(crate) const VOUCHER_EXPIRED: &str = "ERR_VOUCHER_EXPIRED";
+pub(crate) const INVALID_VOUCHER: &str = "ERR_INVALID_VOUCHER";
+pub(crate) const OUTDATED_VOUCHER: &str = "ERR_OUTDATED_VOUCHER";
+pub(crate) const NO_PERMISSIONS_CONTRACT: &str = "ERR_NO_PERMISSIONS_CONTRACT";
+pub(crate) const STILL_APPROVED: &str = "ERR_STILL_APPROVED";
+pub(crate) const WRONG_IN_TOKEN: &str = "ERR_WRONG_IN_TOKEN";
+pub(crate) const INVALID_SALE_CREATE_TRANSFER: &str = "ERR_INVALID_SALE_CREATE_TRANSFER";
+pub(crate) const NOT_ENOUGH_STORAGE_BALANCE: &str = "ERR_NOT_ENOUGH_STORAGE_BALANCE";
+pub(crate) const FORCE_UNREGISTER: &str = "ERR_FORCE_UNREGISTER_NOT_SUPPORTED";
+pub(crate) const TOKEN_IN_USE: &str = "ERR_TOKEN_IN_USE";
+pub(crate) const NON_ZERO_BALANCE: &str = "ERR_NON_ZERO_BALANCE";
+pub(crate) const ACCOUNT_HAS_SUBSCRIPTIONS: &str = "ERR_ACCOUNT_HAS_SUBSCRIPTIONS";
+pub(crate) const ACCOUNT_HAS_ACTIVE_SALES: &str = "ERR_ACCOUNT_HAS_ACTIVE_SALES";
+pub(crate) const SELF_TRANSFER: &str = "ERR_SELF_TRANSFER";
+pub(crate) const ZERO_AMOUNT: &str = "ERR_ZERO_AMOUNT";
+pub(crate) const INVALID_TOKEN_ID: &str = "ERR_INVALID_TOKEN_ID";
+pub(crate) const NOT_TOKEN_OWNER: &str = "ERR_NOT_TOKEN_OWNER";
+pub(crate) const TOKEN_NOT_FOUND: &str = "ERR_TOKEN_NOT_FOUND";
+pub(crate) const NOT_APPROVED_FOR_TOKEN: &str = "ERR_NOT_APPROVED_FOR_TOKEN";
+pub(crate) const RECEIVER_HAS_SUBSCRIPTION: &str = "ERR_RECEIVER_HAS_SUBSCRIPTION";
+pub(crate) const NOT_OPERATOR: &str = "ERR_NOT_OPERATOR";
+pub(crate) const OPERATOR_EXPIRED: &str = "ERR_OPERATOR_EXPIRED";
+pub(crate) const SPENDING_LIMIT_EXCEEDED: &str = "ERR_SPENDING_LIMIT_EXCEEDED";
+pub(crate) const SELF_OPERATOR: &str = "ERR_SELF_OPERATOR";
+pub(crate) const NO_SUBSCRIPTION: &str = "ERR_NO_SUBSCRIPTION";
+pub(crate) const INVALID_MAX_PRICE: &str = "ERR_INVALID_MAX_PRICE";
@@ -1,18 +1,56 @@
 use crate::*;
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
-use near_sdk::is_promise_success;
 use near_sdk::json_types::WrappedBalance;
+use near_sdk::{is_promise_success, serde_json, PromiseOrValue, PromiseResult};
+use std::collections::HashMap;
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub enum FtOnTransferArgs {
     AccountDeposit,
     DonateToTreasury,
+    /// Deposits the transferred in tokens into the sale. The unused tokens are refunded.
+    SaleDeposit {
+        sale_id: u64,
+        referral_id: Option<ValidAccountId>,
+    },
+    /// Creates the sale of the transferred out token. The listing fee is paid in wNEAR.
+    SaleCreate {
+        sale: Box<SaleInput>,
+    },
 }
 
 #[ext_contract(ext_permission_contract)]
 trait ExtPermissionContract {
     fn is_approved(&mut self, account_id: AccountId, sale_id: u64);
+
+    /// Returns the allocation of the account in the sale, or `None` if it's not approved.
+    fn get_allocation(&mut self, account_id: AccountId, sale_id: u64);
+}
+
+#[ext_contract(ext_skyward_receiver)]
+pub trait SkywardReceiver {
+    /// Returns the amount of unused tokens that should be returned to the sender.
+    fn on_skyward_transfer(
+        &mut self,
+        sender_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+        msg: String,
+    ) -> PromiseOrValue<WrappedBalance>;
+}
+
+/// How the account is permitted to deposit into the sale.
+pub enum DepositPermission {
+    Unchecked,
+    Approved { allocation: Option<Balance> },
+    Voucher(SaleVoucher),
+}
+
+/// The permissions check that has to pass before the account can join the sale.
+pub struct PermissionsCheck {
+    pub permissions_contract_id: AccountId,
+    pub with_allocations: bool,
 }
 
 #[ext_contract(ext_self)]
@@ -24,22 +62,88 @@ trait SelfCallbacks {
         amount: WrappedBalance,
     ) -> bool;
 
+    fn after_ft_transfer_call(
+        &mut self,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance;
+
     fn after_near_deposit(&mut self, amount: WrappedBalance) -> bool;
 
+    fn after_sale_near_deposit(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+    ) -> bool;
+
+    fn sale_deposit_w_near(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+    );
+
+    fn after_near_withdraw(&mut self, account_id: AccountId, amount: WrappedBalance) -> bool;
+
     fn after_is_approved(
         &mut self,
         sale_id: u64,
         account_id: AccountId,
+        payer_id: AccountId,
         in_amount: WrappedBalance,
         referral_id: Option<AccountId>,
         attached_deposit: WrappedBalance,
     );
 
-    fn maybe_refund_deposit(
+    fn after_get_allocation(
         &mut self,
+        sale_id: u64,
         account_id: AccountId,
+        payer_id: AccountId,
+        in_amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        attached_deposit: WrappedBalance,
+    );
+
+    fn maybe_refund_deposit(
+        &mut self,
+        payer_id: AccountId,
         attached_deposit: WrappedBalance,
     ) -> bool;
+
+    fn after_force_exit_check(&mut self, sale_id: u64, account_id: AccountId);
+
+    fn after_force_exit_allocation_check(&mut self, sale_id: u64, account_id: AccountId);
+
+    fn after_transfer_permissions_check(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        with_allocations: bool,
+    ) -> WrappedBalance;
+
+    fn after_transfer_call(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance;
+
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool;
 }
 
 trait SelfCallbacks {
@@ -50,23 +154,95 @@ trait SelfCallbacks {
         amount: WrappedBalance,
     ) -> bool;
 
+    fn after_ft_transfer_call(
+        &mut self,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance;
+
     fn after_near_deposit(&mut self, amount: WrappedBalance) -> bool;
 
+    fn after_sale_near_deposit(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+    ) -> bool;
+
+    fn sale_deposit_w_near(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+    );
+
+    fn after_near_withdraw(&mut self, account_id: AccountId, amount: WrappedBalance) -> bool;
+
     fn after_is_approved(
         &mut self,
         is_approved: bool,
         sale_id: u64,
         account_id: AccountId,
+        payer_id: AccountId,
         in_amount: WrappedBalance,
         referral_id: Option<AccountId>,
         attached_deposit: WrappedBalance,
     );
 
-    fn maybe_refund_deposit(
+    fn after_get_allocation(
         &mut self,
+        allocation: Option<WrappedBalance>,
+        sale_id: u64,
         account_id: AccountId,
+        payer_id: AccountId,
+        in_amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        attached_deposit: WrappedBalance,
+    );
+
+    fn maybe_refund_deposit(
+        &mut self,
+        payer_id: AccountId,
         attached_deposit: WrappedBalance,
     ) -> bool;
+
+    fn after_force_exit_check(&mut self, is_approved: bool, sale_id: u64, account_id: AccountId);
+
+    fn after_force_exit_allocation_check(
+        &mut self,
+        allocation: Option<WrappedBalance>,
+        sale_id: u64,
+        account_id: AccountId,
+    );
+
+    fn after_transfer_permissions_check(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        with_allocations: bool,
+    ) -> WrappedBalance;
+
+    fn after_transfer_call(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance;
+
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool;
 }
 
 impl Contract {
@@ -75,9 +251,20 @@ impl Contract {
         account_id: &AccountId,
         token_account_id: &AccountId,
         amount: Balance,
+    ) -> Promise {
+        self.internal_ft_transfer_to(account_id, account_id, token_account_id, amount)
+    }
+
+    /// Transfers the tokens withdrawn from the account to the receiver.
+    pub fn internal_ft_transfer_to(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        token_account_id: &AccountId,
+        amount: Balance,
     ) -> Promise {
         ext_fungible_token::ft_transfer(
-            account_id.clone(),
+            receiver_id.clone(),
             amount.into(),
             None,
             &token_account_id,
@@ -93,6 +280,300 @@ impl Contract {
             AFTER_FT_TRANSFER_GAS,
         ))
     }
+
+    /// Checks permissions with the permissions contract and deposits the in amount if approved.
+    pub fn internal_check_permissions(
+        &mut self,
+        permissions_check: PermissionsCheck,
+        sale_id: u64,
+        account_id: &AccountId,
+        payer_id: &AccountId,
+        in_amount: Balance,
+        referral_id: Option<AccountId>,
+    ) -> Promise {
+        let attached_deposit = env::attached_deposit();
+        self.treasury.locked_attached_deposits += attached_deposit;
+        let permissions_contract_id = &permissions_check.permissions_contract_id;
+        let promise = if permissions_check.with_allocations {
+            ext_permission_contract::get_allocation(
+                account_id.clone(),
+                sale_id,
+                permissions_contract_id,
+                NO_DEPOSIT,
+                PERMISSION_CONTRACT_GAS,
+            )
+            .then(ext_self::after_get_allocation(
+                sale_id,
+                account_id.clone(),
+                payer_id.clone(),
+                in_amount.into(),
+                referral_id,
+                attached_deposit.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                AFTER_IS_APPROVED_GAS,
+            ))
+        } else {
+            ext_permission_contract::is_approved(
+                account_id.clone(),
+                sale_id,
+                permissions_contract_id,
+                NO_DEPOSIT,
+                PERMISSION_CONTRACT_GAS,
+            )
+            .then(ext_self::after_is_approved(
+                sale_id,
+                account_id.clone(),
+                payer_id.clone(),
+                in_amount.into(),
+                referral_id,
+                attached_deposit.into(),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                AFTER_IS_APPROVED_GAS,
+            ))
+        };
+        promise.then(ext_self::maybe_refund_deposit(
+            payer_id.clone(),
+            attached_deposit.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            MAYBE_REFUND_DEPOSIT_GAS,
+        ))
+    }
+
+    /// Deposits the in tokens received with `ft_transfer_call` into the sale.
+    pub fn internal_sale_deposit_on_transfer(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: Balance,
+        referral_id: Option<AccountId>,
+    ) -> PromiseOrValue<WrappedBalance> {
+        match self.internal_deposit_transferred_amount(
+            sale_id,
+            &account_id,
+            &token_account_id,
+            amount,
+            referral_id.as_ref(),
+            DepositPermission::Unchecked,
+        ) {
+            Ok(unused_amount) => PromiseOrValue::Value(unused_amount.into()),
+            Err(permissions_check) => {
+                let permissions_contract_id = &permissions_check.permissions_contract_id;
+                let promise = if permissions_check.with_allocations {
+                    ext_permission_contract::get_allocation(
+                        account_id.clone(),
+                        sale_id,
+                        permissions_contract_id,
+                        NO_DEPOSIT,
+                        PERMISSION_CONTRACT_GAS,
+                    )
+                } else {
+                    ext_permission_contract::is_approved(
+                        account_id.clone(),
+                        sale_id,
+                        permissions_contract_id,
+                        NO_DEPOSIT,
+                        PERMISSION_CONTRACT_GAS,
+                    )
+                };
+                promise
+                    .then(ext_self::after_transfer_permissions_check(
+                        sale_id,
+                        account_id,
+                        token_account_id,
+                        amount.into(),
+                        referral_id,
+                        permissions_check.with_allocations,
+                        &env::current_account_id(),
+                        NO_DEPOSIT,
+                        AFTER_IS_APPROVED_GAS,
+                    ))
+                    .into()
+            }
+        }
+    }
+
+    /// Returns the unused amount, or the permissions check that has to pass first.
+    fn internal_deposit_transferred_amount(
+        &mut self,
+        sale_id: u64,
+        account_id: &AccountId,
+        token_account_id: &AccountId,
+        amount: Balance,
+        referral_id: Option<&AccountId>,
+        permission: DepositPermission,
+    ) -> Result<Balance, PermissionsCheck> {
+        let sale = self.internal_unwrap_sale(sale_id);
+        assert_eq!(
+            &sale.in_token_account_id,
+            token_account_id,
+            "{}",
+            errors::WRONG_IN_TOKEN
+        );
+        if sale.has_ended() || sale.cancelled {
+            log!("{}", errors::SALE_ENDED);
+            return Ok(amount);
+        }
+        let initial_storage_usage = env::storage_usage();
+        let mut account = self.internal_unwrap_account(account_id);
+        let initial_balance = account
+            .balances
+            .get(token_account_id)
+            .expect(errors::TOKEN_NOT_REGISTERED);
+        account.internal_token_deposit(token_account_id, amount);
+        if let Some(permissions_check) =
+            self.internal_deposit_in_amount(sale_id, account_id, amount, referral_id, permission)
+        {
+            account.internal_token_withdraw(token_account_id, amount);
+            return Err(permissions_check);
+        }
+        self.internal_charge_storage(
+            account_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+        );
+
+        let mut account = self.internal_unwrap_account(account_id);
+        let balance = account.balances.get(token_account_id).unwrap();
+        let unused_amount = std::cmp::min(balance.saturating_sub(initial_balance), amount);
+        if unused_amount > 0 {
+            account.internal_token_withdraw(token_account_id, unused_amount);
+        }
+        Ok(unused_amount)
+    }
+
+    /// Pays for the used storage from the account's storage balance, then from its wNEAR balance.
+    pub fn internal_charge_storage(&mut self, account_id: &AccountId, storage_used: StorageUsage) {
+        let storage_cost = env::storage_byte_cost() * Balance::from(storage_used);
+        if storage_cost > 0 {
+            let mut account = self.internal_unwrap_account(account_id);
+            let storage_balance_amount = std::cmp::min(storage_cost, account.storage_balance);
+            self.internal_storage_withdraw(&mut account, storage_balance_amount);
+            let w_near_amount = storage_cost - storage_balance_amount;
+            if w_near_amount > 0 {
+                let w_near_token_id = self.treasury.w_near_token_id.clone();
+                account.internal_token_withdraw(&w_near_token_id, w_near_amount);
+                self.treasury
+                    .internal_deposit(&w_near_token_id, w_near_amount);
+            }
+            self.accounts.insert(account_id, &account.into());
+        }
+    }
+
+    /// Deposits the tokens to the account, or to treasury if the account was closed.
+    pub fn internal_deposit_or_donate(
+        &mut self,
+        account_id: &AccountId,
+        token_account_id: &AccountId,
+        amount: Balance,
+    ) {
+        if let Some(account) = self.accounts.get(account_id) {
+            let mut account: Account = account.into();
+            self.internal_maybe_register_token(&mut account, token_account_id);
+            account.internal_token_deposit(token_account_id, amount);
+            self.accounts.insert(account_id, &account.into());
+        } else {
+            log!(
+                "{} {}, donating {} of {} to treasury",
+                errors::ACCOUNT_NOT_FOUND,
+                account_id,
+                amount,
+                token_account_id
+            );
+            self.treasury.internal_donate(token_account_id, amount);
+        }
+    }
+
+    /// Moves the tokens between the balances of two accounts.
+    pub fn internal_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_account_id: &AccountId,
+        amount: Balance,
+        memo: Option<String>,
+    ) {
+        assert_ne!(sender_id, receiver_id, "{}", errors::SELF_TRANSFER);
+        assert!(amount > 0, "{}", errors::ZERO_AMOUNT);
+        let mut sender = self.internal_unwrap_account(sender_id);
+        let mut receiver = self.internal_unwrap_account(receiver_id);
+        sender.internal_token_withdraw(token_account_id, amount);
+        receiver.internal_token_deposit(token_account_id, amount);
+        log!(
+            "Transfer {} of token {} from {} to {}",
+            amount,
+            token_account_id,
+            sender_id,
+            receiver_id
+        );
+        if let Some(memo) = memo {
+            log!("Memo: {}", memo);
+        }
+    }
+
+    /// Transfers the tokens withdrawn from the account to the receiver with `ft_transfer_call`.
+    pub fn internal_ft_transfer_call(
+        &mut self,
+        account_id: &AccountId,
+        receiver_id: &AccountId,
+        token_account_id: &AccountId,
+        amount: Balance,
+        msg: String,
+    ) -> Promise {
+        ext_fungible_token::ft_transfer_call(
+            receiver_id.clone(),
+            amount.into(),
+            None,
+            msg,
+            &token_account_id,
+            ONE_YOCTO,
+            FT_TRANSFER_CALL_GAS,
+        )
+        .then(ext_self::after_ft_transfer_call(
+            account_id.clone(),
+            token_account_id.clone(),
+            amount.into(),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            AFTER_FT_TRANSFER_CALL_GAS,
+        ))
+    }
+
+    /// Deposits into the sale after the permissions check passed. Returns the storage used.
+    fn internal_deposit_after_permissions_check(
+        &mut self,
+        sale_id: u64,
+        account_id: &AccountId,
+        payer_id: &AccountId,
+        in_amount: Balance,
+        referral_id: Option<&AccountId>,
+        allocation: Option<Balance>,
+    ) -> StorageUsage {
+        let initial_storage_usage = env::storage_usage();
+        let in_token_account_id = self.internal_unwrap_sale(sale_id).in_token_account_id;
+        let initial_in_balance = self.internal_token_balance(account_id, &in_token_account_id);
+
+        assert!(self
+            .internal_deposit_in_amount(
+                sale_id,
+                account_id,
+                in_amount,
+                referral_id,
+                DepositPermission::Approved { allocation },
+            )
+            .is_none());
+
+        // The payer is the operator if it deposited for the account.
+        self.internal_operator_spend(
+            account_id,
+            payer_id,
+            &in_token_account_id,
+            initial_in_balance - self.internal_token_balance(account_id, &in_token_account_id),
+        );
+        env::storage_usage() - initial_storage_usage
+    }
 }
 
 #[near_bindgen]
@@ -113,12 +594,133 @@ impl SelfCallbacks for Contract {
                 token_account_id,
                 amount.0
             );
-            let mut account = self.internal_unwrap_account(&account_id);
-            account.internal_token_deposit(&token_account_id, amount.0);
+            self.internal_deposit_or_donate(&account_id, &token_account_id, amount.0);
         }
         promise_success
     }
 
+    /// Returns the amount used by the receiver.
+    #[private]
+    fn after_ft_transfer_call(
+        &mut self,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance {
+        let used_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<WrappedBalance>(&value)
+                .map(|used_amount| std::cmp::min(used_amount.0, amount.0))
+                .unwrap_or(amount.0),
+            _ => 0,
+        };
+        let unused_amount = amount.0 - used_amount;
+        if unused_amount > 0 {
+            log!(
+                "Returning {} unused by the receiver to {} token {}",
+                unused_amount,
+                account_id,
+                token_account_id
+            );
+            self.internal_deposit_or_donate(&account_id, &token_account_id, unused_amount);
+        }
+        used_amount.into()
+    }
+
+    /// Returns the unused tokens to the sender and the amount used by the receiver.
+    #[private]
+    fn after_transfer_call(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+    ) -> WrappedBalance {
+        let unused_amount = match env::promise_result(0) {
+            PromiseResult::Successful(value) => serde_json::from_slice::<WrappedBalance>(&value)
+                .map(|unused_amount| std::cmp::min(unused_amount.0, amount.0))
+                .unwrap_or(amount.0),
+            _ => amount.0,
+        };
+        let mut refund = 0;
+        if unused_amount > 0 {
+            let receiver: Option<Account> = self.accounts.get(&receiver_id).map(|a| a.into());
+            let sender: Option<Account> = self.accounts.get(&sender_id).map(|a| a.into());
+            if let (Some(mut receiver), Some(mut sender)) = (receiver, sender) {
+                if sender.balances.get(&token_account_id).is_some() {
+                    let receiver_balance = receiver.balances.get(&token_account_id).unwrap_or(0);
+                    refund = std::cmp::min(unused_amount, receiver_balance);
+                    if refund > 0 {
+                        log!(
+                            "Returning {} unused by {} to {} token {}",
+                            refund,
+                            receiver_id,
+                            sender_id,
+                            token_account_id
+                        );
+                        receiver.internal_token_withdraw(&token_account_id, refund);
+                        sender.internal_token_deposit(&token_account_id, refund);
+                    }
+                }
+            }
+        }
+        (amount.0 - refund).into()
+    }
+
+    /// Returns whether the subscription stays with the receiver.
+    #[private]
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool {
+        let return_token = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<bool>(&value).unwrap_or(true)
+            }
+            _ => true,
+        };
+        if !return_token {
+            return true;
+        }
+        let (sale_id, owner_id) = match self
+            .subscription_tokens
+            .get(&parse_subscription_token_id(&token_id))
+        {
+            Some(token) => token,
+            None => return true,
+        };
+        if owner_id != receiver_id {
+            return true;
+        }
+        let sale = self.internal_unwrap_sale(sale_id);
+        if sale.cancelled || sale.has_ended() {
+            return true;
+        }
+        let mut previous_owner: Account = match self.accounts.get(&previous_owner_id) {
+            Some(previous_owner) => previous_owner.into(),
+            None => return true,
+        };
+        if previous_owner.subs.get(&sale_id).is_some() {
+            return true;
+        }
+        log!(
+            "Returning {} from {} to {}",
+            token_id,
+            receiver_id,
+            previous_owner_id
+        );
+        self.internal_subscription_transfer(sale_id, &receiver_id, &previous_owner_id, None);
+        if let Some(approved_account_ids) = approved_account_ids {
+            previous_owner = self.internal_unwrap_account(&previous_owner_id);
+            let mut subscription: Subscription = previous_owner.subs.get(&sale_id).unwrap().into();
+            subscription.approved_account_ids = approved_account_ids;
+            previous_owner.subs.insert(&sale_id, &subscription.into());
+        }
+        false
+    }
+
     #[private]
     fn after_near_deposit(&mut self, amount: WrappedBalance) -> bool {
         let promise_success = is_promise_success();
@@ -134,56 +736,207 @@ impl SelfCallbacks for Contract {
     }
 
     #[private]
-    fn after_is_approved(
+    fn after_sale_near_deposit(
         &mut self,
-        #[callback] is_approved: bool,
         sale_id: u64,
         account_id: AccountId,
-        in_amount: WrappedBalance,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+    ) -> bool {
+        self.treasury.locked_attached_deposits -= amount.0;
+        let promise_success = is_promise_success();
+        if promise_success {
+            let w_near_token_id = self.treasury.w_near_token_id.clone();
+            self.internal_deposit_or_donate(&account_id, &w_near_token_id, amount.0);
+            // Depositing in a separate receipt, so a failed deposit keeps the wNEAR balance.
+            ext_self::sale_deposit_w_near(
+                sale_id,
+                account_id,
+                amount,
+                referral_id,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                SALE_DEPOSIT_W_NEAR_GAS,
+            );
+        } else {
+            log!("Failed to wrap {} NEAR tokens for {}", amount.0, account_id);
+            Promise::new(account_id).transfer(amount.0);
+        }
+        promise_success
+    }
+
+    #[private]
+    fn sale_deposit_w_near(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        amount: WrappedBalance,
         referral_id: Option<AccountId>,
-        attached_deposit: WrappedBalance,
     ) {
-        assert!(is_approved, "{}", errors::NOT_APPROVED);
         let initial_storage_usage = env::storage_usage();
-
-        assert!(self
-            .internal_deposit_in_amount(
+        assert!(
+            self.internal_deposit_in_amount(
                 sale_id,
                 &account_id,
-                in_amount.0,
+                amount.0,
                 referral_id.as_ref(),
-                true,
+                DepositPermission::Unchecked,
             )
-            .is_none());
-
-        let attached_deposit = attached_deposit.0;
-        let required_cost =
-            env::storage_byte_cost() * Balance::from(env::storage_usage() - initial_storage_usage);
-        assert!(
-            required_cost <= attached_deposit,
-            "{} {}",
-            errors::NOT_ENOUGH_ATTACHED_BALANCE,
-            required_cost,
+            .is_none(),
+            "{}",
+            errors::NOT_APPROVED
         );
+        self.internal_charge_storage(
+            &account_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+        );
+    }
 
-        let refund = attached_deposit - required_cost;
-        if refund > 1 {
-            Promise::new(account_id).transfer(refund);
+    #[private]
+    fn after_near_withdraw(&mut self, account_id: AccountId, amount: WrappedBalance) -> bool {
+        let promise_success = is_promise_success();
+        if promise_success {
+            Promise::new(account_id).transfer(amount.0);
+        } else {
+            log!(
+                "{} by {} token {} amount {}",
+                errors::TOKEN_WITHDRAW_FAILED,
+                account_id,
+                self.treasury.w_near_token_id,
+                amount.0
+            );
+            let w_near_token_id = self.treasury.w_near_token_id.clone();
+            self.internal_deposit_or_donate(&account_id, &w_near_token_id, amount.0);
         }
-        self.treasury.locked_attached_deposits -= attached_deposit;
+        promise_success
     }
 
     #[private]
-    fn maybe_refund_deposit(
+    fn after_is_approved(
+        &mut self,
+        #[callback] is_approved: bool,
+        sale_id: u64,
+        account_id: AccountId,
+        payer_id: AccountId,
+        in_amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        attached_deposit: WrappedBalance,
+    ) {
+        assert!(is_approved, "{}", errors::NOT_APPROVED);
+        let storage_used = self.internal_deposit_after_permissions_check(
+            sale_id,
+            &account_id,
+            &payer_id,
+            in_amount.0,
+            referral_id.as_ref(),
+            None,
+        );
+        self.treasury.locked_attached_deposits -= attached_deposit.0;
+        self.internal_pay_storage(&payer_id, storage_used, attached_deposit.0);
+    }
+
+    #[private]
+    fn after_get_allocation(
         &mut self,
+        #[callback] allocation: Option<WrappedBalance>,
+        sale_id: u64,
         account_id: AccountId,
+        payer_id: AccountId,
+        in_amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        attached_deposit: WrappedBalance,
+    ) {
+        let allocation = allocation.expect(errors::NOT_APPROVED);
+        let storage_used = self.internal_deposit_after_permissions_check(
+            sale_id,
+            &account_id,
+            &payer_id,
+            in_amount.0,
+            referral_id.as_ref(),
+            Some(allocation.0),
+        );
+        self.treasury.locked_attached_deposits -= attached_deposit.0;
+        self.internal_pay_storage(&payer_id, storage_used, attached_deposit.0);
+    }
+
+    #[private]
+    fn maybe_refund_deposit(
+        &mut self,
+        payer_id: AccountId,
         attached_deposit: WrappedBalance,
     ) -> bool {
         let promise_success = is_promise_success();
         if !promise_success {
             self.treasury.locked_attached_deposits -= attached_deposit.0;
-            Promise::new(account_id).transfer(attached_deposit.0);
+            Promise::new(payer_id).transfer(attached_deposit.0);
         }
         promise_success
     }
+
+    #[private]
+    fn after_force_exit_check(
+        &mut self,
+        #[callback] is_approved: bool,
+        sale_id: u64,
+        account_id: AccountId,
+    ) {
+        assert!(!is_approved, "{}", errors::STILL_APPROVED);
+        self.internal_force_exit(sale_id, &account_id);
+    }
+
+    #[private]
+    fn after_force_exit_allocation_check(
+        &mut self,
+        #[callback] allocation: Option<WrappedBalance>,
+        sale_id: u64,
+        account_id: AccountId,
+    ) {
+        assert!(allocation.is_none(), "{}", errors::STILL_APPROVED);
+        self.internal_force_exit(sale_id, &account_id);
+    }
+
+    #[private]
+    fn after_transfer_permissions_check(
+        &mut self,
+        sale_id: u64,
+        account_id: AccountId,
+        token_account_id: AccountId,
+        amount: WrappedBalance,
+        referral_id: Option<AccountId>,
+        with_allocations: bool,
+    ) -> WrappedBalance {
+        // `Some(allocation)` if the account is approved.
+        let approval: Option<Option<Balance>> = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                if with_allocations {
+                    serde_json::from_slice::<Option<WrappedBalance>>(&value)
+                        .ok()
+                        .flatten()
+                        .map(|allocation| Some(allocation.0))
+                } else {
+                    serde_json::from_slice::<bool>(&value)
+                        .ok()
+                        .filter(|is_approved| *is_approved)
+                        .map(|_| None)
+                }
+            }
+            _ => None,
+        };
+        if let Some(allocation) = approval {
+            self.internal_deposit_transferred_amount(
+                sale_id,
+                &account_id,
+                &token_account_id,
+                amount.0,
+                referral_id.as_ref(),
+                DepositPermission::Approved { allocation },
+            )
+            .ok()
+            .unwrap()
+            .into()
+        } else {
+            log!("{}", errors::NOT_APPROVED);
+            amount
+        }
+    }
 }
@@ -1,6 +1,8 @@
 pub mod account;
 pub(crate) mod errors;
 mod internal;
+pub mod nft;
+pub mod operator;
 pub mod sale;
 pub mod sub;
 pub mod treasury;
@@ -8,13 +10,15 @@ pub(crate) mod utils;
 
 pub use crate::account::*;
 pub use crate::internal::*;
+pub use crate::nft::*;
+pub use crate::operator::*;
 pub use crate::sale::*;
 pub use crate::sub::*;
 pub use crate::treasury::*;
 pub(crate) use crate::utils::*;
 
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LookupMap;
+use near_sdk::collections::{LookupMap, UnorderedMap};
 use near_sdk::json_types::{ValidAccountId, WrappedBalance};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
@@ -33,6 +37,15 @@ pub(crate) enum StorageKey {
     Sales,
     TreasuryBalances,
     VestingSchedule,
+    SubscriptionTokens,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldContract {
+    pub accounts: LookupMap<AccountId, VAccount>,
+    pub sales: LookupMap<u64, VSale>,
+    pub num_sales: u64,
+    pub treasury: OldTreasury,
 }
 
 #[near_bindgen]
@@ -45,6 +58,11 @@ pub struct Contract {
     pub num_sales: u64,
 
     pub treasury: Treasury,
+
+    /// Sale IDs and account IDs of subscriptions by their non-fungible token IDs.
+    pub subscription_tokens: UnorderedMap<u64, (u64, AccountId)>,
+
+    pub next_subscription_token_id: u64,
 }
 
 #[near_bindgen]
@@ -66,6 +84,22 @@ impl Contract {
                 listing_fee_near.0,
                 w_near_token_id.into(),
             ),
+            subscription_tokens: UnorderedMap::new(StorageKey::SubscriptionTokens),
+            next_subscription_token_id: 0,
+        }
+    }
+
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old_contract: OldContract = env::state_read().expect("Not initialized");
+        Self {
+            accounts: old_contract.accounts,
+            sales: old_contract.sales,
+            num_sales: old_contract.num_sales,
+            treasury: old_contract.treasury.into(),
+            subscription_tokens: UnorderedMap::new(StorageKey::SubscriptionTokens),
+            next_subscription_token_id: 0,
         }
     }
 }
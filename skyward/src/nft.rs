@@ -0,0 +1,427 @@
+use crate::*;
+use near_sdk::json_types::{Base64VecU8, U128};
+use near_sdk::serde_json::json;
+use near_sdk::{assert_one_yocto, PromiseOrValue};
+use std::collections::HashMap;
+
+pub const NFT_METADATA_SPEC: &str = "nft-1.0.0";
+
+/// The ID of the subscription token is a decimal number.
+pub type TokenId = String;
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    /// JSON with `sale_id`, `shares` and `transferable` of the subscription.
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Token {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub metadata: Option<TokenMetadata>,
+    pub approved_account_ids: HashMap<AccountId, u64>,
+}
+
+#[ext_contract(ext_nft_receiver)]
+pub trait NonFungibleTokenReceiver {
+    /// Returns `true` if the token should be returned to the previous owner.
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool>;
+}
+
+#[ext_contract(ext_nft_approval_receiver)]
+pub trait NonFungibleTokenApprovalReceiver {
+    fn nft_on_approve(
+        &mut self,
+        token_id: TokenId,
+        owner_id: AccountId,
+        approval_id: u64,
+        msg: String,
+    ) -> PromiseOrValue<String>;
+}
+
+pub(crate) fn parse_subscription_token_id(token_id: &TokenId) -> u64 {
+    token_id
+        .parse()
+        .unwrap_or_else(|_| env::panic(errors::INVALID_TOKEN_ID.as_bytes()))
+}
+
+impl Contract {
+    pub fn internal_nft_token(&self, sale_id: u64, owner_id: &AccountId) -> Option<Token> {
+        let account: Account = self.accounts.get(owner_id)?.into();
+        let subscription: Subscription = account.subs.get(&sale_id)?.into();
+        let sale = self.internal_unwrap_sale(sale_id);
+        Some(Token {
+            token_id: subscription.token_id?.to_string(),
+            owner_id: owner_id.clone(),
+            metadata: Some(TokenMetadata {
+                title: Some(format!("Subscription to sale #{}", sale_id)),
+                description: Some(sale.title.clone()),
+                media: None,
+                media_hash: None,
+                copies: Some(1),
+                issued_at: None,
+                // The token can't be transferred after the sale ends.
+                expires_at: Some(((sale.start_time + sale.duration) / 10u64.pow(6)).to_string()),
+                starts_at: None,
+                updated_at: None,
+                extra: Some(
+                    json!({
+                        "sale_id": sale_id,
+                        "shares": U128::from(subscription.shares),
+                        "transferable": !sale.cancelled && !sale.has_ended(),
+                    })
+                    .to_string(),
+                ),
+                reference: None,
+                reference_hash: None,
+            }),
+            approved_account_ids: subscription.approved_account_ids,
+        })
+    }
+
+    /// Returns the sale ID, the owner ID, the owner's account and the subscription of the token.
+    pub fn internal_unwrap_subscription_token(
+        &self,
+        token_id: &TokenId,
+    ) -> (u64, AccountId, Account, Subscription) {
+        let (sale_id, owner_id) = self
+            .subscription_tokens
+            .get(&parse_subscription_token_id(token_id))
+            .expect(errors::TOKEN_NOT_FOUND);
+        let account = self.internal_unwrap_account(&owner_id);
+        let subscription: Subscription = account
+            .subs
+            .get(&sale_id)
+            .expect(errors::TOKEN_NOT_FOUND)
+            .into();
+        (sale_id, owner_id, account, subscription)
+    }
+
+    /// Returns the previous owner and the approvals the token had.
+    fn internal_nft_transfer(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        token_id: &TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) -> (AccountId, HashMap<AccountId, u64>) {
+        let (sale_id, owner_id, _, subscription) =
+            self.internal_unwrap_subscription_token(token_id);
+        if sender_id != &owner_id {
+            let actual_approval_id = subscription
+                .approved_account_ids
+                .get(sender_id)
+                .expect(errors::NOT_APPROVED_FOR_TOKEN);
+            if let Some(approval_id) = approval_id {
+                assert_eq!(
+                    actual_approval_id,
+                    &approval_id,
+                    "{}",
+                    errors::NOT_APPROVED_FOR_TOKEN
+                );
+            }
+        }
+        let receiver = self.internal_unwrap_account(receiver_id);
+        assert!(
+            receiver.subs.get(&sale_id).is_none(),
+            "{}",
+            errors::RECEIVER_HAS_SUBSCRIPTION
+        );
+        let initial_storage_usage = env::storage_usage();
+        self.internal_subscription_transfer(sale_id, &owner_id, receiver_id, None);
+        let storage_used = env::storage_usage().saturating_sub(initial_storage_usage);
+        if sender_id == &owner_id {
+            self.internal_charge_storage(&owner_id, storage_used);
+        } else {
+            self.internal_pay_storage(sender_id, storage_used, env::attached_deposit());
+        }
+        log!("Transfer {} from {} to {}", token_id, owner_id, receiver_id);
+        if let Some(memo) = memo {
+            log!("Memo: {}", memo);
+        }
+        (owner_id, subscription.approved_account_ids)
+    }
+
+    /// Saves the approvals of the subscription token.
+    fn internal_save_token_approvals(
+        &mut self,
+        owner_id: &AccountId,
+        account: &mut Account,
+        sale_id: u64,
+        subscription: Subscription,
+    ) {
+        let initial_storage_usage = env::storage_usage();
+        account.subs.insert(&sale_id, &subscription.into());
+        let storage_usage = env::storage_usage();
+        if storage_usage >= initial_storage_usage {
+            self.internal_pay_storage(
+                owner_id,
+                storage_usage - initial_storage_usage,
+                env::attached_deposit(),
+            );
+        } else {
+            refund_released_storage(owner_id, initial_storage_usage - storage_usage);
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Transfers the whole subscription to a receiver without a subscription to the sale.
+    #[payable]
+    pub fn nft_transfer(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    ) {
+        assert_at_least_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        self.internal_nft_transfer(
+            &sender_id,
+            receiver_id.as_ref(),
+            &token_id,
+            approval_id,
+            memo,
+        );
+    }
+
+    /// Transfers the whole subscription to the receiver and calls `nft_on_transfer` on it.
+    #[payable]
+    pub fn nft_transfer_call(
+        &mut self,
+        receiver_id: ValidAccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        assert_at_least_one_yocto();
+        let sender_id = env::predecessor_account_id();
+        let (previous_owner_id, approved_account_ids) = self.internal_nft_transfer(
+            &sender_id,
+            receiver_id.as_ref(),
+            &token_id,
+            approval_id,
+            memo,
+        );
+        ext_nft_receiver::nft_on_transfer(
+            sender_id,
+            previous_owner_id.clone(),
+            token_id.clone(),
+            msg,
+            receiver_id.as_ref(),
+            NO_DEPOSIT,
+            NFT_ON_TRANSFER_GAS,
+        )
+        .then(ext_self::nft_resolve_transfer(
+            previous_owner_id,
+            receiver_id.into(),
+            token_id,
+            Some(approved_account_ids),
+            &env::current_account_id(),
+            NO_DEPOSIT,
+            NFT_RESOLVE_TRANSFER_GAS,
+        ))
+        .into()
+    }
+
+    /// Approves the account to transfer the subscription token.
+    #[payable]
+    pub fn nft_approve(
+        &mut self,
+        token_id: TokenId,
+        account_id: ValidAccountId,
+        msg: Option<String>,
+    ) -> Option<Promise> {
+        assert_at_least_one_yocto();
+        let (sale_id, owner_id, mut account, mut subscription) =
+            self.internal_unwrap_subscription_token(&token_id);
+        assert_eq!(
+            owner_id,
+            env::predecessor_account_id(),
+            "{}",
+            errors::NOT_TOKEN_OWNER
+        );
+        let approval_id = subscription.next_approval_id;
+        subscription.next_approval_id += 1;
+        subscription
+            .approved_account_ids
+            .insert(account_id.clone().into(), approval_id);
+        self.internal_save_token_approvals(&owner_id, &mut account, sale_id, subscription);
+        msg.map(|msg| {
+            ext_nft_approval_receiver::nft_on_approve(
+                token_id,
+                owner_id,
+                approval_id,
+                msg,
+                account_id.as_ref(),
+                NO_DEPOSIT,
+                NFT_ON_APPROVE_GAS,
+            )
+        })
+    }
+
+    /// Revokes the approval of the account. Requires 1 yocto NEAR.
+    #[payable]
+    pub fn nft_revoke(&mut self, token_id: TokenId, account_id: ValidAccountId) {
+        assert_one_yocto();
+        let (sale_id, owner_id, mut account, mut subscription) =
+            self.internal_unwrap_subscription_token(&token_id);
+        assert_eq!(
+            owner_id,
+            env::predecessor_account_id(),
+            "{}",
+            errors::NOT_TOKEN_OWNER
+        );
+        if subscription
+            .approved_account_ids
+            .remove(account_id.as_ref())
+            .is_some()
+        {
+            self.internal_save_token_approvals(&owner_id, &mut account, sale_id, subscription);
+        }
+    }
+
+    /// Revokes all approvals of the token. Requires 1 yocto NEAR.
+    #[payable]
+    pub fn nft_revoke_all(&mut self, token_id: TokenId) {
+        assert_one_yocto();
+        let (sale_id, owner_id, mut account, mut subscription) =
+            self.internal_unwrap_subscription_token(&token_id);
+        assert_eq!(
+            owner_id,
+            env::predecessor_account_id(),
+            "{}",
+            errors::NOT_TOKEN_OWNER
+        );
+        if !subscription.approved_account_ids.is_empty() {
+            subscription.approved_account_ids.clear();
+            self.internal_save_token_approvals(&owner_id, &mut account, sale_id, subscription);
+        }
+    }
+
+    pub fn nft_is_approved(
+        &self,
+        token_id: TokenId,
+        approved_account_id: ValidAccountId,
+        approval_id: Option<u64>,
+    ) -> bool {
+        let (_, _, _, subscription) = self.internal_unwrap_subscription_token(&token_id);
+        match subscription
+            .approved_account_ids
+            .get(approved_account_id.as_ref())
+        {
+            Some(actual_approval_id) => approval_id
+                .map(|approval_id| *actual_approval_id == approval_id)
+                .unwrap_or(true),
+            None => false,
+        }
+    }
+
+    pub fn nft_token(&self, token_id: TokenId) -> Option<Token> {
+        let (sale_id, owner_id) = self
+            .subscription_tokens
+            .get(&parse_subscription_token_id(&token_id))?;
+        self.internal_nft_token(sale_id, &owner_id)
+    }
+
+    pub fn nft_total_supply(&self) -> U128 {
+        U128::from(self.subscription_tokens.len() as u128)
+    }
+
+    pub fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Token> {
+        let values = self.subscription_tokens.values_as_vector();
+        let from_index = from_index.map(|i| i.0 as u64).unwrap_or(0);
+        let limit = limit.unwrap_or(values.len());
+        (from_index..std::cmp::min(from_index + limit, values.len()))
+            .filter_map(|index| {
+                let (sale_id, owner_id) = values.get(index).unwrap();
+                self.internal_nft_token(sale_id, &owner_id)
+            })
+            .collect()
+    }
+
+    pub fn nft_supply_for_owner(&self, account_id: ValidAccountId) -> U128 {
+        self.accounts
+            .get(account_id.as_ref())
+            .map(|account| {
+                let account: Account = account.into();
+                let num_tokens = account
+                    .subs
+                    .values()
+                    .map(Subscription::from)
+                    .filter(|subscription| subscription.token_id.is_some())
+                    .count();
+                U128::from(num_tokens as u128)
+            })
+            .unwrap_or_else(|| U128::from(0))
+    }
+
+    pub fn nft_tokens_for_owner(
+        &self,
+        account_id: ValidAccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Vec<Token> {
+        if let Some(account) = self.accounts.get(account_id.as_ref()) {
+            let account: Account = account.into();
+            let keys = account.subs.keys_as_vector();
+            let from_index = from_index.map(|i| i.0 as u64).unwrap_or(0);
+            let limit = limit.unwrap_or(keys.len());
+            (from_index..std::cmp::min(from_index + limit, keys.len()))
+                .filter_map(|index| {
+                    self.internal_nft_token(keys.get(index).unwrap(), account_id.as_ref())
+                })
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    pub fn nft_metadata(&self) -> NFTContractMetadata {
+        NFTContractMetadata {
+            spec: NFT_METADATA_SPEC.to_string(),
+            name: "Skyward Subscriptions".to_string(),
+            symbol: "SKYSUB".to_string(),
+            icon: None,
+            base_uri: None,
+            reference: None,
+            reference_hash: None,
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::*;
+use near_sdk::json_types::{WrappedBalance, WrappedTimestamp};
+use near_sdk::{assert_one_yocto, Timestamp};
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct Operator {
+    pub account_id: AccountId,
+    /// The remaining amounts of in tokens the operator can deposit. `None` means no limits.
+    pub spending_limits: Option<Vec<(TokenAccountId, Balance)>>,
+    pub expires_at: Option<Timestamp>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq))]
+pub struct OperatorOutput {
+    pub account_id: AccountId,
+    pub spending_limits: Option<Vec<(TokenAccountId, WrappedBalance)>>,
+    pub expires_at: Option<WrappedTimestamp>,
+}
+
+impl From<&Operator> for OperatorOutput {
+    fn from(operator: &Operator) -> Self {
+        Self {
+            account_id: operator.account_id.clone(),
+            spending_limits: operator.spending_limits.as_ref().map(|spending_limits| {
+                spending_limits
+                    .iter()
+                    .map(|(token_account_id, amount)| (token_account_id.clone(), (*amount).into()))
+                    .collect()
+            }),
+            expires_at: operator.expires_at.map(|t| t.into()),
+        }
+    }
+}
+
+impl Operator {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .map(|expires_at| expires_at <= env::block_timestamp())
+            .unwrap_or(false)
+    }
+
+    /// Deducts the amount from the spending limit of the token.
+    pub fn internal_spend(&mut self, token_account_id: &TokenAccountId, amount: Balance) {
+        if let Some(spending_limits) = &mut self.spending_limits {
+            let (_, limit) = spending_limits
+                .iter_mut()
+                .find(|(t, _)| t == token_account_id)
+                .expect(errors::SPENDING_LIMIT_EXCEEDED);
+            *limit = limit
+                .checked_sub(amount)
+                .expect(errors::SPENDING_LIMIT_EXCEEDED);
+        }
+    }
+}
+
+impl Contract {
+    /// Returns the account the predecessor acts for as itself or its operator.
+    pub fn internal_acting_account_id(&self, account_id: Option<ValidAccountId>) -> AccountId {
+        let predecessor_id = env::predecessor_account_id();
+        let account_id: AccountId = match account_id {
+            Some(account_id) if account_id.as_ref() != &predecessor_id => account_id.into(),
+            _ => return predecessor_id,
+        };
+        let account = self.internal_unwrap_account(&account_id);
+        let operator = account
+            .operators
+            .iter()
+            .find(|operator| operator.account_id == predecessor_id)
+            .expect(errors::NOT_OPERATOR);
+        assert!(!operator.is_expired(), "{}", errors::OPERATOR_EXPIRED);
+        account_id
+    }
+
+    /// Deducts the in tokens the operator deposited for the account from its spending limit.
+    pub fn internal_operator_spend(
+        &mut self,
+        account_id: &AccountId,
+        operator_id: &AccountId,
+        token_account_id: &TokenAccountId,
+        amount: Balance,
+    ) {
+        if account_id == operator_id || amount == 0 {
+            return;
+        }
+        let mut account = self.internal_unwrap_account(account_id);
+        account
+            .operators
+            .iter_mut()
+            .find(|operator| &operator.account_id == operator_id)
+            .expect(errors::NOT_OPERATOR)
+            .internal_spend(token_account_id, amount);
+        self.accounts.insert(account_id, &account.into());
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Approves the operator to deposit, withdraw and claim in sales on behalf of the account.
+    #[payable]
+    pub fn approve_operator(
+        &mut self,
+        operator_id: ValidAccountId,
+        spending_limits: Option<Vec<(ValidAccountId, WrappedBalance)>>,
+        expires_at: Option<WrappedTimestamp>,
+    ) {
+        assert_at_least_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        assert_ne!(
+            operator_id.as_ref(),
+            &account_id,
+            "{}",
+            errors::SELF_OPERATOR
+        );
+        let mut account = self.internal_unwrap_account(&account_id);
+        account
+            .operators
+            .retain(|operator| &operator.account_id != operator_id.as_ref());
+        account.operators.push(Operator {
+            account_id: operator_id.into(),
+            spending_limits: spending_limits.map(|spending_limits| {
+                spending_limits
+                    .into_iter()
+                    .map(|(token_account_id, amount)| (token_account_id.into(), amount.0))
+                    .collect()
+            }),
+            expires_at: expires_at.map(|t| t.0),
+        });
+        self.accounts.insert(&account_id, &account.into());
+        self.internal_pay_storage(
+            &account_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+            env::attached_deposit(),
+        );
+    }
+
+    #[payable]
+    pub fn revoke_operator(&mut self, operator_id: ValidAccountId) {
+        assert_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        let mut account = self.internal_unwrap_account(&account_id);
+        let num_operators = account.operators.len();
+        account
+            .operators
+            .retain(|operator| &operator.account_id != operator_id.as_ref());
+        assert_ne!(
+            num_operators,
+            account.operators.len(),
+            "{}",
+            errors::NOT_OPERATOR
+        );
+        self.accounts.insert(&account_id, &account.into());
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    pub fn get_operators(&self, account_id: ValidAccountId) -> Vec<OperatorOutput> {
+        self.accounts
+            .get(account_id.as_ref())
+            .map(|account| {
+                let account: Account = account.into();
+                account.operators.iter().map(|o| o.into()).collect()
+            })
+            .unwrap_or_default()
+    }
+}
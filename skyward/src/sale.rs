@@ -1,6 +1,11 @@
 use crate::*;
-use near_sdk::json_types::{WrappedBalance, WrappedDuration, WrappedTimestamp};
-use near_sdk::{assert_one_yocto, BlockHeight, Duration, Timestamp};
+use ed25519_dalek::Verifier;
+use near_sdk::json_types::{
+    Base58CryptoHash, Base58PublicKey, Base64VecU8, WrappedBalance, WrappedDuration,
+    WrappedTimestamp,
+};
+use near_sdk::{assert_one_yocto, BlockHeight, CryptoHash, Duration, PublicKey, Timestamp};
+use std::convert::{TryFrom, TryInto};
 
 const MIN_DURATION_BEFORE_START: Duration = 7 * 24 * 60 * 60 * 1_000_000_000;
 const MAX_DURATION_BEFORE_START: Duration = 365 * 24 * 60 * 60 * 1_000_000_000;
@@ -23,7 +28,29 @@ pub struct OldSale {
     pub url: Option<String>,
     pub permissions_contract_id: Option<AccountId>,
 
-    pub out_tokens: Vec<SaleOutToken>,
+    pub out_tokens: Vec<OldSaleOutToken>,
+
+    pub in_token_account_id: AccountId,
+    pub in_token_remaining: Balance,
+    pub in_token_paid_unclaimed: Balance,
+    pub in_token_paid: Balance,
+
+    pub start_time: Timestamp,
+    pub duration: Duration,
+
+    pub total_shares: Balance,
+    pub last_timestamp: Timestamp,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct SaleV2 {
+    pub owner_id: AccountId,
+
+    pub title: String,
+    pub url: Option<String>,
+    pub permissions_contract_id: Option<AccountId>,
+
+    pub out_tokens: Vec<OldSaleOutToken>,
 
     pub in_token_account_id: AccountId,
     pub in_token_remaining: Balance,
@@ -35,6 +62,9 @@ pub struct OldSale {
 
     pub total_shares: Balance,
     pub last_timestamp: Timestamp,
+
+    pub start_block_height: BlockHeight,
+    pub end_block_height: Option<BlockHeight>,
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -61,6 +91,36 @@ pub struct Sale {
 
     pub start_block_height: BlockHeight,
     pub end_block_height: Option<BlockHeight>,
+
+    pub cancelled: bool,
+    /// The minimum amount of in tokens the sale has to raise, otherwise it fails.
+    pub min_in_token_paid: Option<Balance>,
+    /// The maximum amount of in tokens the sale can raise.
+    pub max_in_token: Option<Balance>,
+    /// The maximum total amount of in tokens a single account can deposit.
+    pub max_in_per_account: Option<Balance>,
+    /// The minimum amount of in tokens a single account has to deposit.
+    pub min_in_per_account: Option<Balance>,
+    /// Whether the permissions contract returns allocations instead of approvals.
+    pub permissions_with_allocations: bool,
+    /// The Merkle root of the accounts allowed to deposit.
+    pub whitelist_root: Option<CryptoHash>,
+    /// Whether the whitelist leaves include per-account allocations.
+    pub whitelist_with_allocations: bool,
+    /// The ed25519 public key of the approver signing vouchers off chain.
+    pub voucher_public_key: Option<PublicKey>,
+    /// Whether the permissions contract is checked on every deposit instead of only the first one.
+    pub recheck_permissions: bool,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct OldSaleOutToken {
+    pub token_account_id: TokenAccountId,
+    pub remaining: Balance,
+    pub distributed: Balance,
+    pub treasury_unclaimed: Option<Balance>,
+    pub per_share: InnerU256,
+    pub referral_bpt: Option<BasicPoints>,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Clone)]
@@ -71,11 +131,119 @@ pub struct SaleOutToken {
     pub treasury_unclaimed: Option<Balance>,
     pub per_share: InnerU256,
     pub referral_bpt: Option<BasicPoints>,
+    /// The minimum price for this out token.
+    pub min_price: Option<Price>,
+}
+
+/// The price expressed as the amount of in tokens paid for the amount of out tokens.
+#[derive(BorshSerialize, BorshDeserialize, Clone)]
+pub struct Price {
+    pub in_amount: Balance,
+    pub out_amount: Balance,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+#[cfg_attr(not(target_arch = "wasm32"), derive(Debug, PartialEq, Clone))]
+pub struct SalePrice {
+    pub in_amount: WrappedBalance,
+    pub out_amount: WrappedBalance,
+}
+
+impl From<SalePrice> for Price {
+    fn from(price: SalePrice) -> Self {
+        Self {
+            in_amount: price.in_amount.into(),
+            out_amount: price.out_amount.into(),
+        }
+    }
+}
+
+impl From<Price> for SalePrice {
+    fn from(price: Price) -> Self {
+        Self {
+            in_amount: price.in_amount.into(),
+            out_amount: price.out_amount.into(),
+        }
+    }
+}
+
+/// The proof that the account is in the sale whitelist.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WhitelistProof {
+    /// Hashes of the sibling nodes on the path from the account leaf to the root.
+    pub proof: Vec<Base58CryptoHash>,
+    /// The allocation of the account, if the whitelist has allocations.
+    pub allocation: Option<WrappedBalance>,
+}
+
+/// Returns `sha256(0 || account_id || allocation)`, with the optional allocation in 16 LE bytes.
+pub fn whitelist_leaf_hash(account_id: &AccountId, allocation: Option<Balance>) -> CryptoHash {
+    let mut data = vec![0u8];
+    data.extend_from_slice(account_id.as_bytes());
+    if let Some(allocation) = allocation {
+        data.extend_from_slice(&allocation.to_le_bytes());
+    }
+    env::sha256(&data).try_into().unwrap()
+}
+
+/// Returns the hash of the whitelist node: `sha256(1 || min(a, b) || max(a, b))`.
+pub fn whitelist_node_hash(a: &CryptoHash, b: &CryptoHash) -> CryptoHash {
+    let (left, right) = if a <= b { (a, b) } else { (b, a) };
+    let mut data = vec![1u8];
+    data.extend_from_slice(left);
+    data.extend_from_slice(right);
+    env::sha256(&data).try_into().unwrap()
+}
+
+/// The approval of the account signed off chain by the sale approver.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SaleVoucher {
+    pub allocation: WrappedBalance,
+    pub expires_at: WrappedTimestamp,
+    /// Only vouchers issued later than the last used one are accepted.
+    pub issued_at: WrappedTimestamp,
+    /// The ed25519 signature of the borsh serialized `VoucherMessage`.
+    pub signature: Base64VecU8,
+}
+
+/// The prefix of every `VoucherMessage`, so the signature can't be valid for other messages.
+pub const VOUCHER_MESSAGE_PREFIX: &str = "skyward:sale_voucher";
+
+/// The message signed by the sale approver.
+#[derive(BorshSerialize)]
+pub struct VoucherMessage {
+    /// Always `VOUCHER_MESSAGE_PREFIX`.
+    pub prefix: String,
+    /// The Skyward contract the voucher is valid for, since sale IDs repeat across deployments.
+    pub contract_id: AccountId,
+    pub account_id: AccountId,
+    pub sale_id: u64,
+    pub allocation: Balance,
+    pub expires_at: Timestamp,
+    pub issued_at: Timestamp,
+}
+
+impl From<OldSaleOutToken> for SaleOutToken {
+    fn from(token: OldSaleOutToken) -> Self {
+        Self {
+            token_account_id: token.token_account_id,
+            remaining: token.remaining,
+            distributed: token.distributed,
+            treasury_unclaimed: token.treasury_unclaimed,
+            per_share: token.per_share,
+            referral_bpt: token.referral_bpt,
+            min_price: None,
+        }
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum VSale {
     First(OldSale),
+    Second(SaleV2),
     Current(Sale),
 }
 
@@ -94,7 +262,7 @@ impl From<VSale> for Sale {
                     title: old_sale.title,
                     url: old_sale.url,
                     permissions_contract_id: old_sale.permissions_contract_id,
-                    out_tokens: old_sale.out_tokens,
+                    out_tokens: old_sale.out_tokens.into_iter().map(|o| o.into()).collect(),
                     in_token_account_id: old_sale.in_token_account_id,
                     in_token_remaining: old_sale.in_token_remaining,
                     in_token_paid_unclaimed: old_sale.in_token_paid_unclaimed,
@@ -105,6 +273,47 @@ impl From<VSale> for Sale {
                     last_timestamp: old_sale.last_timestamp,
                     start_block_height: 0,
                     end_block_height: None,
+                    cancelled: false,
+                    min_in_token_paid: None,
+                    max_in_token: None,
+                    max_in_per_account: None,
+                    min_in_per_account: None,
+                    permissions_with_allocations: false,
+                    whitelist_root: None,
+                    whitelist_with_allocations: false,
+                    voucher_public_key: None,
+                    recheck_permissions: false,
+                };
+                sale.touch();
+                sale
+            }
+            VSale::Second(old_sale) => {
+                let mut sale = Sale {
+                    owner_id: old_sale.owner_id,
+                    title: old_sale.title,
+                    url: old_sale.url,
+                    permissions_contract_id: old_sale.permissions_contract_id,
+                    out_tokens: old_sale.out_tokens.into_iter().map(|o| o.into()).collect(),
+                    in_token_account_id: old_sale.in_token_account_id,
+                    in_token_remaining: old_sale.in_token_remaining,
+                    in_token_paid_unclaimed: old_sale.in_token_paid_unclaimed,
+                    in_token_paid: old_sale.in_token_paid,
+                    start_time: old_sale.start_time,
+                    duration: old_sale.duration,
+                    total_shares: old_sale.total_shares,
+                    last_timestamp: old_sale.last_timestamp,
+                    start_block_height: old_sale.start_block_height,
+                    end_block_height: old_sale.end_block_height,
+                    cancelled: false,
+                    min_in_token_paid: None,
+                    max_in_token: None,
+                    max_in_per_account: None,
+                    min_in_per_account: None,
+                    permissions_with_allocations: false,
+                    whitelist_root: None,
+                    whitelist_with_allocations: false,
+                    voucher_public_key: None,
+                    recheck_permissions: false,
                 };
                 sale.touch();
                 sale
@@ -127,6 +336,16 @@ pub struct SaleInput {
 
     pub start_time: WrappedTimestamp,
     pub duration: WrappedDuration,
+
+    pub min_in_token_paid: Option<WrappedBalance>,
+    pub max_in_token: Option<WrappedBalance>,
+    pub max_in_per_account: Option<WrappedBalance>,
+    pub min_in_per_account: Option<WrappedBalance>,
+    pub permissions_with_allocations: Option<bool>,
+    pub whitelist_root: Option<Base58CryptoHash>,
+    pub whitelist_with_allocations: Option<bool>,
+    pub voucher_public_key: Option<Base58PublicKey>,
+    pub recheck_permissions: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -135,6 +354,7 @@ pub struct SaleInputOutToken {
     pub token_account_id: ValidAccountId,
     pub balance: WrappedBalance,
     pub referral_bpt: Option<BasicPoints>,
+    pub min_price: Option<SalePrice>,
 }
 
 impl SaleOutToken {
@@ -147,6 +367,7 @@ impl SaleOutToken {
             treasury_unclaimed: if is_skyward_token { None } else { Some(0) },
             per_share: U256::zero().0,
             referral_bpt: token.referral_bpt,
+            min_price: token.min_price.map(|p| p.into()),
         }
     }
 }
@@ -182,6 +403,19 @@ pub struct SaleOutput {
     pub current_block_height: BlockHeight,
     pub start_block_height: BlockHeight,
     pub end_block_height: Option<BlockHeight>,
+
+    pub cancelled: bool,
+    pub min_in_token_paid: Option<WrappedBalance>,
+    pub failed: bool,
+    pub max_in_token: Option<WrappedBalance>,
+    pub in_token_headroom: Option<WrappedBalance>,
+    pub max_in_per_account: Option<WrappedBalance>,
+    pub min_in_per_account: Option<WrappedBalance>,
+    pub permissions_with_allocations: bool,
+    pub whitelist_root: Option<Base58CryptoHash>,
+    pub whitelist_with_allocations: bool,
+    pub voucher_public_key: Option<Base58PublicKey>,
+    pub recheck_permissions: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -193,6 +427,7 @@ pub struct SaleOutputOutToken {
     pub distributed: WrappedBalance,
     pub treasury_unclaimed: Option<WrappedBalance>,
     pub referral_bpt: Option<BasicPoints>,
+    pub min_price: Option<SalePrice>,
 }
 
 impl From<SaleOutToken> for SaleOutputOutToken {
@@ -203,12 +438,17 @@ impl From<SaleOutToken> for SaleOutputOutToken {
             distributed: token.distributed.into(),
             treasury_unclaimed: token.treasury_unclaimed.map(|b| b.into()),
             referral_bpt: token.referral_bpt,
+            min_price: token.min_price.map(|p| p.into()),
         }
     }
 }
 
 impl Sale {
     pub fn touch(&mut self) {
+        if self.cancelled {
+            // Sale was cancelled before it started.
+            return;
+        }
         let end_time = self.start_time + self.duration;
         let timestamp = std::cmp::min(end_time, env::block_timestamp());
         if timestamp <= self.last_timestamp {
@@ -229,9 +469,20 @@ impl Sale {
         let time_diff = U256::from(timestamp - self.last_timestamp);
         let remaining_duration = U256::from(end_time - self.last_timestamp);
 
+        let in_token_amount =
+            (U256::from(self.in_token_remaining) * time_diff / remaining_duration).as_u128();
+
         for out_token in &mut self.out_tokens {
             let mut amount =
                 (U256::from(out_token.remaining) * time_diff / remaining_duration).as_u128();
+            if let Some(min_price) = &out_token.min_price {
+                // Not releasing more out tokens than the paid in tokens buy at the minimum price.
+                let max_amount = U256::from(in_token_amount) * U256::from(min_price.out_amount)
+                    / U256::from(min_price.in_amount);
+                if max_amount < U256::from(amount) {
+                    amount = max_amount.as_u128();
+                }
+            }
             if amount > 0 {
                 out_token.distributed += amount;
                 out_token.remaining -= amount;
@@ -246,8 +497,6 @@ impl Sale {
             }
         }
 
-        let in_token_amount =
-            (U256::from(self.in_token_remaining) * time_diff / remaining_duration).as_u128();
         self.in_token_paid_unclaimed += in_token_amount;
         self.in_token_paid += in_token_amount;
         self.in_token_remaining -= in_token_amount;
@@ -302,8 +551,46 @@ impl Sale {
                     errors::MAX_REFERRAL_BPT
                 );
             }
+            if let Some(min_price) = &out_token.min_price {
+                assert!(
+                    min_price.in_amount > 0 && min_price.out_amount > 0,
+                    "{}",
+                    errors::INVALID_MIN_PRICE
+                );
+            }
             unique_tokens.push(out_token.token_account_id.clone());
         }
+        if let Some(max_in_token) = self.max_in_token {
+            assert!(
+                max_in_token >= self.min_in_token_paid.unwrap_or(1),
+                "{}",
+                errors::INVALID_MAX_IN_TOKEN
+            );
+        }
+        if let Some(max_in_per_account) = self.max_in_per_account {
+            assert!(
+                max_in_per_account >= self.min_in_per_account.unwrap_or(1),
+                "{}",
+                errors::INVALID_IN_PER_ACCOUNT_LIMITS
+            );
+        }
+        assert!(
+            self.permissions_contract_id.is_some() as u8
+                + self.whitelist_root.is_some() as u8
+                + self.voucher_public_key.is_some() as u8
+                <= 1,
+            "{}",
+            errors::MULTIPLE_SALE_GATES
+        );
+        if let Some(voucher_public_key) = &self.voucher_public_key {
+            assert!(
+                voucher_public_key.len() == 33
+                    && voucher_public_key[0] == 0
+                    && ed25519_dalek::PublicKey::from_bytes(&voucher_public_key[1..]).is_ok(),
+                "{}",
+                errors::INVALID_VOUCHER_PUBLIC_KEY
+            );
+        }
         unique_tokens.sort();
         unique_tokens.dedup();
         assert_eq!(
@@ -340,11 +627,23 @@ impl Sale {
             last_timestamp: start_time,
             start_block_height: env::block_index(),
             end_block_height: None,
+            cancelled: false,
+            min_in_token_paid: sale.min_in_token_paid.map(|b| b.into()),
+            max_in_token: sale.max_in_token.map(|b| b.into()),
+            max_in_per_account: sale.max_in_per_account.map(|b| b.into()),
+            min_in_per_account: sale.min_in_per_account.map(|b| b.into()),
+            permissions_with_allocations: sale.permissions_with_allocations.unwrap_or(false),
+            whitelist_root: sale.whitelist_root.map(|h| h.into()),
+            whitelist_with_allocations: sale.whitelist_with_allocations.unwrap_or(false),
+            voucher_public_key: sale.voucher_public_key.map(|k| k.into()),
+            recheck_permissions: sale.recheck_permissions.unwrap_or(false),
         }
     }
 
     pub fn into_output(self, sale_id: u64, account: Option<&Account>) -> SaleOutput {
         let remaining_duration = self.start_time + self.duration - self.last_timestamp;
+        let failed = self.is_failed();
+        let in_token_headroom = self.in_token_headroom();
         let subscription =
             account.and_then(|account| account.internal_subscription_output(sale_id, &self));
         SaleOutput {
@@ -367,6 +666,18 @@ impl Sale {
             current_block_height: env::block_index(),
             start_block_height: self.start_block_height,
             end_block_height: self.end_block_height,
+            cancelled: self.cancelled,
+            min_in_token_paid: self.min_in_token_paid.map(|b| b.into()),
+            failed,
+            max_in_token: self.max_in_token.map(|b| b.into()),
+            in_token_headroom: in_token_headroom.map(|b| b.into()),
+            max_in_per_account: self.max_in_per_account.map(|b| b.into()),
+            min_in_per_account: self.min_in_per_account.map(|b| b.into()),
+            permissions_with_allocations: self.permissions_with_allocations,
+            whitelist_root: self.whitelist_root.map(|h| h.into()),
+            whitelist_with_allocations: self.whitelist_with_allocations,
+            voucher_public_key: self.voucher_public_key.map(Base58PublicKey),
+            recheck_permissions: self.recheck_permissions,
         }
     }
 
@@ -409,6 +720,120 @@ impl Sale {
     pub fn has_ended(&self) -> bool {
         self.last_timestamp >= self.start_time + self.duration
     }
+
+    pub fn has_started(&self) -> bool {
+        self.last_timestamp > self.start_time
+    }
+
+    /// Whether the sale has a minimum and the proceeds are locked until the sale ends.
+    pub fn is_soft_cap_pending(&self) -> bool {
+        self.min_in_token_paid.is_some() && !self.has_ended()
+    }
+
+    /// Whether the sale has ended without raising the minimum amount of in tokens.
+    pub fn is_failed(&self) -> bool {
+        self.has_ended()
+            && self
+                .min_in_token_paid
+                .map(|min_in_token_paid| self.in_token_paid < min_in_token_paid)
+                .unwrap_or(false)
+    }
+
+    /// Whether accounts have to be approved before the first deposit.
+    pub fn is_gated(&self) -> bool {
+        self.permissions_contract_id.is_some()
+            || self.whitelist_root.is_some()
+            || self.voucher_public_key.is_some()
+    }
+
+    /// Whether subscriptions without shares have to be kept until the sale ends.
+    pub fn keeps_subscriptions(&self) -> bool {
+        self.is_gated() || self.max_in_per_account.is_some() || self.min_in_token_paid.is_some()
+    }
+
+    /// Verifies the whitelist proof of the account and returns its allocation, if any.
+    pub fn verify_whitelist_proof(
+        &self,
+        account_id: &AccountId,
+        whitelist_proof: WhitelistProof,
+    ) -> Option<Balance> {
+        let whitelist_root = self.whitelist_root.expect(errors::NO_WHITELIST);
+        let allocation: Option<Balance> = whitelist_proof.allocation.map(|a| a.into());
+        assert_eq!(
+            allocation.is_some(),
+            self.whitelist_with_allocations,
+            "{}",
+            errors::INVALID_WHITELIST_PROOF
+        );
+        let root = whitelist_proof.proof.into_iter().fold(
+            whitelist_leaf_hash(account_id, allocation),
+            |hash, sibling| whitelist_node_hash(&hash, &sibling.into()),
+        );
+        assert_eq!(root, whitelist_root, "{}", errors::INVALID_WHITELIST_PROOF);
+        allocation
+    }
+
+    /// Verifies the voucher of the account and returns its allocation.
+    pub fn verify_voucher(
+        &self,
+        sale_id: u64,
+        account_id: &AccountId,
+        voucher: SaleVoucher,
+    ) -> Balance {
+        let voucher_public_key = self
+            .voucher_public_key
+            .as_ref()
+            .expect(errors::NO_VOUCHER_PUBLIC_KEY);
+        assert!(
+            env::block_timestamp() < voucher.expires_at.0,
+            "{}",
+            errors::VOUCHER_EXPIRED
+        );
+        let message = VoucherMessage {
+            prefix: VOUCHER_MESSAGE_PREFIX.to_string(),
+            contract_id: env::current_account_id(),
+            account_id: account_id.clone(),
+            sale_id,
+            allocation: voucher.allocation.0,
+            expires_at: voucher.expires_at.0,
+            issued_at: voucher.issued_at.0,
+        }
+        .try_to_vec()
+        .unwrap();
+        let public_key = ed25519_dalek::PublicKey::from_bytes(&voucher_public_key[1..]).unwrap();
+        let signature = ed25519_dalek::Signature::try_from(voucher.signature.0.as_slice())
+            .expect(errors::INVALID_VOUCHER);
+        assert!(
+            public_key.verify(&message, &signature).is_ok(),
+            "{}",
+            errors::INVALID_VOUCHER
+        );
+        voucher.allocation.0
+    }
+
+    /// Panics if a partial withdrawal leaves less than the account minimum in the subscription.
+    pub fn assert_no_dust(&self, remaining_in_balance: Balance) {
+        if let Some(min_in_per_account) = self.min_in_per_account {
+            assert!(
+                remaining_in_balance == 0 || remaining_in_balance >= min_in_per_account,
+                "{}",
+                errors::BELOW_MIN_IN_PER_ACCOUNT
+            );
+        }
+    }
+
+    /// Whether the current price of the first out token is higher than the given price.
+    pub fn is_price_above(&self, price: &Price) -> bool {
+        U256::from(self.in_token_remaining) * U256::from(price.out_amount)
+            > U256::from(self.out_tokens[0].remaining) * U256::from(price.in_amount)
+    }
+
+    /// Returns the amount of in tokens the sale can still accept, if the sale has a maximum.
+    pub fn in_token_headroom(&self) -> Option<Balance> {
+        self.max_in_token.map(|max_in_token| {
+            max_in_token.saturating_sub(self.in_token_remaining + self.in_token_paid)
+        })
+    }
 }
 
 impl Contract {
@@ -427,6 +852,14 @@ impl Contract {
     }
 
     pub fn internal_distribute_unclaimed_tokens(&mut self, sale: &mut Sale) {
+        if sale.is_soft_cap_pending() {
+            // The proceeds are locked until it's known whether the sale reached the minimum.
+            return;
+        }
+        if sale.is_failed() {
+            self.internal_refund_failed_sale(sale);
+            return;
+        }
         if sale.in_token_paid_unclaimed > 0 {
             if &sale.owner_id == &env::current_account_id() {
                 // Skyward Sale
@@ -472,6 +905,85 @@ impl Contract {
             }
         }
     }
+
+    /// Withdraws the out tokens from the owner's balance and saves the new sale.
+    pub fn internal_create_sale(&mut self, sale: Sale) -> u64 {
+        let sale_id = self.num_sales;
+        let mut account = self.internal_unwrap_account(&sale.owner_id);
+        for out_token in &sale.out_tokens {
+            if out_token.remaining > 0 {
+                account.internal_token_withdraw(&out_token.token_account_id, out_token.remaining);
+            }
+        }
+        self.internal_maybe_register_token(&mut account, &sale.in_token_account_id);
+        account.sales.insert(&sale_id);
+
+        self.accounts.insert(&sale.owner_id, &account.into());
+        self.sales.insert(&sale_id, &sale.into());
+        self.num_sales += 1;
+        sale_id
+    }
+
+    /// Creates the sale of the out token received with `ft_transfer_call`.
+    pub fn internal_sale_create_on_transfer(
+        &mut self,
+        owner_id: AccountId,
+        token_account_id: &TokenAccountId,
+        amount: Balance,
+        sale: SaleInput,
+    ) -> u64 {
+        let initial_storage_usage = env::storage_usage();
+        let sale = Sale::from_input(sale, owner_id.clone(), &self.treasury.skyward_token_id);
+        sale.assert_valid_not_started();
+        assert!(
+            sale.out_tokens.len() == 1
+                && &sale.out_tokens[0].token_account_id == token_account_id
+                && sale.out_tokens[0].remaining == amount,
+            "{}",
+            errors::INVALID_SALE_CREATE_TRANSFER
+        );
+
+        let mut account = self.internal_unwrap_account(&owner_id);
+        self.internal_maybe_register_token(&mut account, token_account_id);
+        account.internal_token_deposit(token_account_id, amount);
+        self.accounts.insert(&owner_id, &account.into());
+
+        let sale_id = self.internal_create_sale(sale);
+
+        let w_near_token_id = self.treasury.w_near_token_id.clone();
+        let listing_fee = self.treasury.listing_fee_near;
+        let mut account = self.internal_unwrap_account(&owner_id);
+        account.internal_token_withdraw(&w_near_token_id, listing_fee);
+        self.treasury
+            .internal_deposit(&w_near_token_id, listing_fee);
+        self.internal_charge_storage(
+            &owner_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+        );
+        sale_id
+    }
+
+    /// Returns all out tokens of the failed sale to the owner.
+    pub fn internal_refund_failed_sale(&mut self, sale: &mut Sale) {
+        for out_token in &mut sale.out_tokens {
+            let amount = out_token.remaining + out_token.distributed;
+            if amount > 0 {
+                if sale.owner_id == env::current_account_id() {
+                    self.treasury
+                        .internal_donate(&out_token.token_account_id, amount);
+                } else {
+                    let mut account = self.internal_unwrap_account(&sale.owner_id);
+                    account.internal_token_deposit(&out_token.token_account_id, amount);
+                    self.accounts.insert(&sale.owner_id, &account.into());
+                }
+                out_token.remaining = 0;
+                out_token.distributed = 0;
+            }
+            if let Some(treasury_unclaimed) = &mut out_token.treasury_unclaimed {
+                *treasury_unclaimed = 0;
+            }
+        }
+    }
 }
 
 #[near_bindgen]
@@ -518,23 +1030,15 @@ impl Contract {
             self.sales.insert(&sale_id, &sale.into());
             self.num_sales += 1;
         } else {
-            let mut account = self.internal_unwrap_account(&sale.owner_id);
-            for out_token in &sale.out_tokens {
-                if out_token.remaining > 0 {
-                    account
-                        .internal_token_withdraw(&out_token.token_account_id, out_token.remaining);
-                }
-            }
-            self.internal_maybe_register_token(&mut account, &sale.in_token_account_id);
-            account.sales.insert(&sale_id);
-
-            self.accounts.insert(&sale.owner_id, &account.into());
-            self.sales.insert(&sale_id, &sale.into());
-            self.num_sales += 1;
+            self.internal_create_sale(sale);
 
-            refund_extra_storage_deposit(
+            let deposit = env::attached_deposit()
+                .checked_sub(self.treasury.listing_fee_near)
+                .expect(errors::NOT_ENOUGH_ATTACHED_BALANCE);
+            self.internal_pay_storage(
+                &env::predecessor_account_id(),
                 env::storage_usage() - initial_storage_usage,
-                self.treasury.listing_fee_near,
+                deposit,
             );
         }
         sale_id
@@ -580,71 +1084,319 @@ impl Contract {
         sale_id: u64,
         amount: WrappedBalance,
         referral_id: Option<ValidAccountId>,
+        whitelist_proof: Option<WhitelistProof>,
+        voucher: Option<SaleVoucher>,
+        account_id: Option<ValidAccountId>,
     ) {
         assert_at_least_one_yocto();
         let initial_storage_usage = env::storage_usage();
-        let account_id = env::predecessor_account_id();
+        let in_token_account_id = self.internal_unwrap_sale(sale_id).in_token_account_id;
+        let account_id = self.internal_acting_account_id(account_id);
+        let initial_in_balance = self.internal_token_balance(&account_id, &in_token_account_id);
 
         let referral_id = referral_id.map(|r| r.into());
         let in_amount = amount.0;
 
-        let permissions_contract_id = self.internal_deposit_in_amount(
+        let permission = if let Some(voucher) = voucher {
+            DepositPermission::Voucher(voucher)
+        } else if let Some(whitelist_proof) = whitelist_proof {
+            let sale = self.internal_unwrap_sale(sale_id);
+            DepositPermission::Approved {
+                allocation: sale.verify_whitelist_proof(&account_id, whitelist_proof),
+            }
+        } else {
+            DepositPermission::Unchecked
+        };
+
+        let permissions_check = self.internal_deposit_in_amount(
             sale_id,
             &account_id,
             in_amount,
             referral_id.as_ref(),
-            false,
+            permission,
         );
 
-        if let Some(permissions_contract_id) = permissions_contract_id {
-            let attached_deposit = env::attached_deposit();
-            self.treasury.locked_attached_deposits += env::attached_deposit();
-            ext_permission_contract::is_approved(
-                account_id.clone(),
+        if let Some(permissions_check) = permissions_check {
+            self.internal_check_permissions(
+                permissions_check,
+                sale_id,
+                &account_id,
+                &env::predecessor_account_id(),
+                in_amount,
+                referral_id,
+            )
+            .as_return();
+        } else {
+            // Only the amount accepted by the sale counts towards the spending limit.
+            self.internal_operator_spend(
+                &account_id,
+                &env::predecessor_account_id(),
+                &in_token_account_id,
+                initial_in_balance - self.internal_token_balance(&account_id, &in_token_account_id),
+            );
+            self.internal_pay_storage(
+                &env::predecessor_account_id(),
+                env::storage_usage() - initial_storage_usage,
+                env::attached_deposit(),
+            );
+        }
+    }
+
+    /// Wraps the attached NEAR into wNEAR and deposits it into the sale with wNEAR in token.
+    #[payable]
+    pub fn sale_deposit_near(
+        &mut self,
+        sale_id: u64,
+        referral_id: Option<ValidAccountId>,
+    ) -> Promise {
+        let amount = env::attached_deposit();
+        assert!(amount > 0, "{}", errors::ZERO_IN_AMOUNT);
+        let account_id = env::predecessor_account_id();
+        let sale = self.internal_unwrap_sale(sale_id);
+        assert_eq!(
+            &sale.in_token_account_id,
+            &self.treasury.w_near_token_id,
+            "{}",
+            errors::WRONG_IN_TOKEN
+        );
+        let account = self.internal_unwrap_account(&account_id);
+        assert!(
+            account.balances.get(&sale.in_token_account_id).is_some(),
+            "{}",
+            errors::TOKEN_NOT_REGISTERED
+        );
+        self.treasury.locked_attached_deposits += amount;
+        Promise::new(self.treasury.w_near_token_id.clone())
+            .function_call(
+                b"near_deposit".to_vec(),
+                b"{}".to_vec(),
+                amount,
+                NEAR_DEPOSIT_GAS,
+            )
+            .then(ext_self::after_sale_near_deposit(
+                sale_id,
+                account_id,
+                amount.into(),
+                referral_id.map(|r| r.into()),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                AFTER_SALE_NEAR_DEPOSIT_GAS,
+            ))
+    }
+
+    #[payable]
+    pub fn sale_withdraw_in_token(
+        &mut self,
+        sale_id: u64,
+        shares: Option<WrappedBalance>,
+        account_id: Option<ValidAccountId>,
+    ) {
+        assert_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = self.internal_acting_account_id(account_id);
+        self.internal_withdraw_shares(sale_id, &account_id, shares.map(|s| s.0));
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    #[payable]
+    pub fn sale_withdraw_in_token_exact(
+        &mut self,
+        sale_id: u64,
+        amount: WrappedBalance,
+        account_id: Option<ValidAccountId>,
+    ) {
+        assert_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = self.internal_acting_account_id(account_id);
+        self.internal_withdraw_in_token_exact(sale_id, &account_id, amount.0);
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    /// Transfers the given shares, or all shares, of the account's subscription to the receiver.
+    #[payable]
+    pub fn subscription_transfer(
+        &mut self,
+        sale_id: u64,
+        receiver_id: ValidAccountId,
+        shares: Option<WrappedBalance>,
+    ) {
+        assert_at_least_one_yocto();
+        let initial_storage_usage = env::storage_usage();
+        let account_id = env::predecessor_account_id();
+        self.internal_subscription_transfer(
+            sale_id,
+            &account_id,
+            receiver_id.as_ref(),
+            shares.map(|s| s.0),
+        );
+        self.internal_pay_storage(
+            &account_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+            env::attached_deposit(),
+        );
+    }
+
+    /// Cancels the sale before it starts. Can only be called by the sale owner.
+    #[payable]
+    pub fn sale_cancel(&mut self, sale_id: u64) {
+        assert_one_yocto();
+        let mut sale = self.internal_unwrap_sale(sale_id);
+        assert_eq!(
+            &sale.owner_id,
+            &env::predecessor_account_id(),
+            "{}",
+            errors::NOT_SALE_OWNER
+        );
+        assert_ne!(
+            &sale.owner_id,
+            &env::current_account_id(),
+            "{}",
+            errors::CANNOT_CANCEL_SKYWARD_SALE
+        );
+        assert!(!sale.cancelled, "{}", errors::SALE_CANCELLED);
+        assert!(!sale.has_started(), "{}", errors::SALE_STARTED);
+
+        let mut account = self.internal_unwrap_account(&sale.owner_id);
+        for out_token in &mut sale.out_tokens {
+            if out_token.remaining > 0 {
+                account.internal_token_deposit(&out_token.token_account_id, out_token.remaining);
+                out_token.remaining = 0;
+            }
+        }
+        sale.cancelled = true;
+
+        self.accounts.insert(&sale.owner_id, &account.into());
+        self.sales.insert(&sale_id, &sale.into());
+    }
+
+    /// Withdraws the remaining in tokens of the account that is no longer approved.
+    #[payable]
+    pub fn sale_force_exit(&mut self, sale_id: u64, account_id: ValidAccountId) -> Promise {
+        assert_one_yocto();
+        let sale = self.internal_unwrap_sale(sale_id);
+        assert_eq!(
+            &sale.owner_id,
+            &env::predecessor_account_id(),
+            "{}",
+            errors::NOT_SALE_OWNER
+        );
+        assert!(!sale.has_ended(), "{}", errors::SALE_ENDED);
+        let permissions_contract_id = sale
+            .permissions_contract_id
+            .expect(errors::NO_PERMISSIONS_CONTRACT);
+        if sale.permissions_with_allocations {
+            ext_permission_contract::get_allocation(
+                account_id.clone().into(),
                 sale_id,
                 &permissions_contract_id,
                 NO_DEPOSIT,
                 PERMISSION_CONTRACT_GAS,
             )
-            .then(ext_self::after_is_approved(
+            .then(ext_self::after_force_exit_allocation_check(
                 sale_id,
-                account_id.clone(),
-                in_amount.into(),
-                referral_id,
-                attached_deposit.into(),
+                account_id.into(),
                 &env::current_account_id(),
                 NO_DEPOSIT,
                 AFTER_IS_APPROVED_GAS,
             ))
-            .then(ext_self::maybe_refund_deposit(
-                account_id.clone(),
-                attached_deposit.into(),
+        } else {
+            ext_permission_contract::is_approved(
+                account_id.clone().into(),
+                sale_id,
+                &permissions_contract_id,
+                NO_DEPOSIT,
+                PERMISSION_CONTRACT_GAS,
+            )
+            .then(ext_self::after_force_exit_check(
+                sale_id,
+                account_id.into(),
                 &env::current_account_id(),
                 NO_DEPOSIT,
-                MAYBE_REFUND_DEPOSIT_GAS,
+                AFTER_IS_APPROVED_GAS,
             ))
-            .as_return();
-        } else {
-            refund_extra_storage_deposit(env::storage_usage() - initial_storage_usage, 0);
         }
     }
 
+    /// Sets or removes the maximum price of the first out token the account accepts in the sale.
     #[payable]
-    pub fn sale_withdraw_in_token(&mut self, sale_id: u64, shares: Option<WrappedBalance>) {
-        assert_one_yocto();
+    pub fn sale_set_max_price(&mut self, sale_id: u64, max_price: Option<SalePrice>) {
+        assert_at_least_one_yocto();
         let initial_storage_usage = env::storage_usage();
         let account_id = env::predecessor_account_id();
-        self.internal_withdraw_shares(sale_id, &account_id, shares.map(|s| s.0));
-        refund_released_storage(&account_id, initial_storage_usage - env::storage_usage());
+        let mut sale = self.internal_unwrap_sale(sale_id);
+        self.internal_distribute_unclaimed_tokens(&mut sale);
+        let mut account = self.internal_unwrap_account(&account_id);
+        assert!(
+            account.subs.get(&sale_id).is_some(),
+            "{}",
+            errors::NO_SUBSCRIPTION
+        );
+        let mut subscription =
+            self.internal_update_subscription(&mut account, sale_id, &mut sale, None, false);
+        subscription.max_price = max_price.map(|max_price| {
+            let max_price: Price = max_price.into();
+            assert!(
+                max_price.in_amount > 0 && max_price.out_amount > 0,
+                "{}",
+                errors::INVALID_MAX_PRICE
+            );
+            max_price
+        });
+        self.internal_save_subscription(&account_id, &mut account, sale_id, &sale, subscription);
+        self.accounts.insert(&account_id, &account.into());
+        self.sales.insert(&sale_id, &sale.into());
+        self.internal_pay_storage(
+            &account_id,
+            env::storage_usage().saturating_sub(initial_storage_usage),
+            env::attached_deposit(),
+        );
     }
 
-    #[payable]
-    pub fn sale_withdraw_in_token_exact(&mut self, sale_id: u64, amount: WrappedBalance) {
-        assert_one_yocto();
-        let initial_storage_usage = env::storage_usage();
-        let account_id = env::predecessor_account_id();
-        self.internal_withdraw_in_token_exact(sale_id, &account_id, amount.0);
-        refund_released_storage(&account_id, initial_storage_usage - env::storage_usage());
+    /// Withdraws the in tokens of the accounts whose maximum price is below the current price.
+    pub fn sale_enforce_limits(
+        &mut self,
+        sale_id: u64,
+        account_ids: Vec<ValidAccountId>,
+    ) -> Vec<AccountId> {
+        let mut exited_account_ids = vec![];
+        for account_id in account_ids {
+            let account_id: AccountId = account_id.into();
+            let sale = self.internal_unwrap_sale(sale_id);
+            let subscription: Option<Subscription> = self
+                .accounts
+                .get(&account_id)
+                .and_then(|account| {
+                    let account: Account = account.into();
+                    account.subs.get(&sale_id)
+                })
+                .map(|subscription| subscription.into());
+            let is_above_limit = subscription
+                .map(|subscription| {
+                    sale.shares_to_in_balance(subscription.shares) > 0
+                        && subscription
+                            .max_price
+                            .map(|max_price| sale.is_price_above(&max_price))
+                            .unwrap_or(false)
+                })
+                .unwrap_or(false);
+            if is_above_limit {
+                log!("Price limit of {} is reached", account_id);
+                let initial_storage_usage = env::storage_usage();
+                self.internal_withdraw_shares(sale_id, &account_id, None);
+                refund_released_storage(
+                    &account_id,
+                    initial_storage_usage.saturating_sub(env::storage_usage()),
+                );
+                exited_account_ids.push(account_id);
+            }
+        }
+        exited_account_ids
     }
 
     /// This method can be called by anyone in order to move in tokens to treasury
@@ -654,19 +1406,91 @@ impl Contract {
         self.sales.insert(&sale_id, &sale.into());
     }
 
-    pub fn sale_claim_out_tokens(&mut self, sale_id: u64) {
-        let account_id = env::predecessor_account_id();
+    pub fn sale_claim_out_tokens(&mut self, sale_id: u64, account_id: Option<ValidAccountId>) {
         let initial_storage_usage = env::storage_usage();
+        let account_id = self.internal_acting_account_id(account_id);
         let mut sale = self.internal_unwrap_sale(sale_id);
         self.internal_distribute_unclaimed_tokens(&mut sale);
         let mut account = self.internal_unwrap_account(&account_id);
         let subscription =
             self.internal_update_subscription(&mut account, sale_id, &mut sale, None, false);
 
-        account.internal_save_subscription(sale_id, &sale, subscription);
+        self.internal_save_subscription(&account_id, &mut account, sale_id, &sale, subscription);
 
         self.accounts.insert(&account_id, &account.into());
         self.sales.insert(&sale_id, &sale.into());
-        refund_released_storage(&account_id, initial_storage_usage - env::storage_usage());
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    /// Claims out tokens from the given sales, and withdraws them if `withdraw` is set.
+    pub fn claim_all(
+        &mut self,
+        sale_ids: Vec<u64>,
+        withdraw: bool,
+    ) -> Vec<(TokenAccountId, WrappedBalance)> {
+        let account_id = env::predecessor_account_id();
+        let initial_storage_usage = env::storage_usage();
+        let mut claimed: Vec<(TokenAccountId, Balance)> = vec![];
+        for sale_id in sale_ids {
+            let mut sale = self.internal_unwrap_sale(sale_id);
+            self.internal_distribute_unclaimed_tokens(&mut sale);
+            let mut account = self.internal_unwrap_account(&account_id);
+            let token_account_ids: Vec<TokenAccountId> = sale
+                .out_tokens
+                .iter()
+                .map(|out_token| out_token.token_account_id.clone())
+                .chain(std::iter::once(sale.in_token_account_id.clone()))
+                .collect();
+            let initial_balances: Vec<Balance> = token_account_ids
+                .iter()
+                .map(|token_account_id| account.balances.get(token_account_id).unwrap_or(0))
+                .collect();
+            let subscription =
+                self.internal_update_subscription(&mut account, sale_id, &mut sale, None, false);
+            self.internal_save_subscription(
+                &account_id,
+                &mut account,
+                sale_id,
+                &sale,
+                subscription,
+            );
+
+            for (token_account_id, initial_balance) in
+                token_account_ids.into_iter().zip(initial_balances)
+            {
+                let amount = account.balances.get(&token_account_id).unwrap_or(0) - initial_balance;
+                if amount > 0 {
+                    if let Some((_, total)) =
+                        claimed.iter_mut().find(|(t, _)| t == &token_account_id)
+                    {
+                        *total += amount;
+                    } else {
+                        claimed.push((token_account_id, amount));
+                    }
+                }
+            }
+
+            self.accounts.insert(&account_id, &account.into());
+            self.sales.insert(&sale_id, &sale.into());
+        }
+        refund_released_storage(
+            &account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+
+        if withdraw {
+            let mut account = self.internal_unwrap_account(&account_id);
+            for (token_account_id, amount) in &claimed {
+                account.internal_token_withdraw(token_account_id, *amount);
+                self.internal_ft_transfer(&account_id, token_account_id, *amount);
+            }
+        }
+        claimed
+            .into_iter()
+            .map(|(token_account_id, amount)| (token_account_id, amount.into()))
+            .collect()
     }
 }
@@ -1,5 +1,17 @@
 use crate::*;
 use near_sdk::json_types::WrappedBalance;
+use near_sdk::Timestamp;
+use std::collections::HashMap;
+
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct OldSubscription {
+    pub shares: Balance,
+    pub last_in_balance: Balance,
+    pub spent_in_balance_without_shares: Balance,
+    pub last_out_token_per_share: Vec<InnerU256>,
+    pub claimed_out_balance: Vec<Balance>,
+    pub referral_id: Option<AccountId>,
+}
 
 #[derive(BorshSerialize, BorshDeserialize)]
 pub struct Subscription {
@@ -9,10 +21,27 @@ pub struct Subscription {
     pub last_out_token_per_share: Vec<InnerU256>,
     pub claimed_out_balance: Vec<Balance>,
     pub referral_id: Option<AccountId>,
+    /// Out tokens that are locked until the sale with a minimum ends.
+    pub locked_out_balance: Vec<Balance>,
+    /// The maximum amount of in tokens the account can deposit, given by the permissions contract.
+    pub allocation: Option<Balance>,
+    /// The total amount of in tokens the account deposited. Withdrawals don't reduce it.
+    pub deposited_in_balance: Balance,
+    /// The maximum price of the first out token the account accepts.
+    pub max_price: Option<Price>,
+    /// The ID of the non-fungible token of the subscription.
+    pub token_id: Option<u64>,
+    /// Accounts approved to transfer the subscription token, with their approval IDs.
+    pub approved_account_ids: HashMap<AccountId, u64>,
+    /// The approval ID for the next approved account. It moves with the token.
+    pub next_approval_id: u64,
+    /// When the last voucher used by the account was issued.
+    pub voucher_issued_at: Option<Timestamp>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize)]
 pub enum VSubscription {
+    First(OldSubscription),
     Current(Subscription),
 }
 
@@ -25,6 +54,23 @@ impl From<Subscription> for VSubscription {
 impl From<VSubscription> for Subscription {
     fn from(v_subscription: VSubscription) -> Self {
         match v_subscription {
+            VSubscription::First(old_subscription) => Subscription {
+                shares: old_subscription.shares,
+                last_in_balance: old_subscription.last_in_balance,
+                spent_in_balance_without_shares: old_subscription.spent_in_balance_without_shares,
+                locked_out_balance: vec![0; old_subscription.claimed_out_balance.len()],
+                last_out_token_per_share: old_subscription.last_out_token_per_share,
+                claimed_out_balance: old_subscription.claimed_out_balance,
+                referral_id: old_subscription.referral_id,
+                allocation: None,
+                deposited_in_balance: old_subscription.spent_in_balance_without_shares
+                    + old_subscription.last_in_balance,
+                max_price: None,
+                token_id: None,
+                approved_account_ids: HashMap::new(),
+                next_approval_id: 0,
+                voucher_issued_at: None,
+            },
             VSubscription::Current(subscription) => subscription,
         }
     }
@@ -40,6 +86,7 @@ pub struct SubscriptionOutput {
     pub claimed_out_balance: Vec<WrappedBalance>,
     pub shares: WrappedBalance,
     pub referral_id: Option<AccountId>,
+    pub max_price: Option<SalePrice>,
 }
 
 impl Subscription {
@@ -76,8 +123,21 @@ impl Subscription {
                 .collect(),
             claimed_out_balance: vec![0; sale.out_tokens.len()],
             referral_id,
+            locked_out_balance: vec![0; sale.out_tokens.len()],
+            allocation: None,
+            deposited_in_balance: 0,
+            max_price: None,
+            token_id: None,
+            approved_account_ids: HashMap::new(),
+            next_approval_id: 0,
+            voucher_issued_at: None,
         }
     }
+
+    /// Whether the subscription is no longer needed and can be removed.
+    pub fn is_removable(&self, sale: &Sale) -> bool {
+        self.shares == 0 && (!sale.keeps_subscriptions() || sale.has_ended() || sale.cancelled)
+    }
 }
 
 impl Contract {
@@ -111,12 +171,171 @@ impl Contract {
         sale.in_token_remaining -= in_token_amount;
 
         subscription.last_in_balance = sale.shares_to_in_balance(subscription.shares);
+        sale.assert_no_dust(subscription.last_in_balance);
 
-        account.internal_save_subscription(sale_id, &sale, subscription);
+        self.internal_save_subscription(account_id, &mut account, sale_id, &sale, subscription);
         self.accounts.insert(&account_id, &account.into());
         self.sales.insert(&sale_id, &sale.into());
     }
 
+    /// Withdraws all remaining in tokens of the account, keeping the subscription.
+    pub fn internal_force_exit(&mut self, sale_id: u64, account_id: &AccountId) {
+        let initial_storage_usage = env::storage_usage();
+        let mut sale = self.internal_unwrap_sale(sale_id);
+        self.internal_distribute_unclaimed_tokens(&mut sale);
+        let mut account = self.internal_unwrap_account(account_id);
+        let mut subscription =
+            self.internal_update_subscription(&mut account, sale_id, &mut sale, None, false);
+        let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
+        subscription.spent_in_balance_without_shares +=
+            subscription.last_in_balance - remaining_in_balance;
+        if remaining_in_balance > 0 {
+            account.internal_token_deposit(&sale.in_token_account_id, remaining_in_balance);
+        }
+        sale.total_shares -= subscription.shares;
+        sale.in_token_remaining -= remaining_in_balance;
+        subscription.shares = 0;
+        subscription.last_in_balance = 0;
+
+        self.internal_save_subscription(account_id, &mut account, sale_id, &sale, subscription);
+        self.accounts.insert(account_id, &account.into());
+        self.sales.insert(&sale_id, &sale.into());
+        refund_released_storage(
+            account_id,
+            initial_storage_usage.saturating_sub(env::storage_usage()),
+        );
+    }
+
+    /// Moves the shares with their part of the spent in tokens and the locked out tokens.
+    pub fn internal_subscription_transfer(
+        &mut self,
+        sale_id: u64,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: Option<Balance>,
+    ) {
+        assert_ne!(sender_id, receiver_id, "{}", errors::SELF_TRANSFER);
+        let mut sale = self.internal_unwrap_sale(sale_id);
+        assert!(!sale.cancelled, "{}", errors::SALE_CANCELLED);
+        assert!(!sale.has_ended(), "{}", errors::SALE_ENDED);
+        self.internal_distribute_unclaimed_tokens(&mut sale);
+
+        let mut sender = self.internal_unwrap_account(sender_id);
+        let mut sender_subscription =
+            self.internal_update_subscription(&mut sender, sale_id, &mut sale, None, false);
+        let shares = shares.unwrap_or(sender_subscription.shares);
+        assert!(shares > 0, "{}", errors::ZERO_SHARES);
+        assert!(
+            shares <= sender_subscription.shares,
+            "{}",
+            errors::NOT_ENOUGH_SHARES
+        );
+
+        let mut receiver = self.internal_unwrap_account(receiver_id);
+        let receiver_shares = receiver
+            .subs
+            .get(&sale_id)
+            .map(|subscription| Subscription::from(subscription).shares);
+        if sale.permissions_contract_id.is_some() {
+            // Subscriptions without shares have to pass the permissions check again.
+            assert!(
+                receiver_shares.unwrap_or(0) > 0,
+                "{}",
+                errors::NO_PERMISSION
+            );
+        }
+        let moves_token = shares == sender_subscription.shares && receiver_shares.is_none();
+        for out_token in &sale.out_tokens {
+            self.internal_maybe_register_token(&mut receiver, &out_token.token_account_id);
+        }
+        let referral_id = sender_subscription
+            .referral_id
+            .clone()
+            .filter(|referral_id| referral_id != receiver_id);
+        let mut receiver_subscription = self.internal_update_subscription(
+            &mut receiver,
+            sale_id,
+            &mut sale,
+            referral_id.as_ref(),
+            false,
+        );
+
+        for subscription in [&mut sender_subscription, &mut receiver_subscription].iter_mut() {
+            let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
+            subscription.spent_in_balance_without_shares +=
+                subscription.last_in_balance - remaining_in_balance;
+        }
+        let spent_in_balance = (U256::from(sender_subscription.spent_in_balance_without_shares)
+            * U256::from(shares)
+            / U256::from(sender_subscription.shares))
+        .as_u128();
+        let deposited_in_balance = (U256::from(sender_subscription.deposited_in_balance)
+            * U256::from(shares)
+            / U256::from(sender_subscription.shares))
+        .as_u128();
+        for (sender_locked_amount, receiver_locked_amount) in sender_subscription
+            .locked_out_balance
+            .iter_mut()
+            .zip(receiver_subscription.locked_out_balance.iter_mut())
+        {
+            let locked_amount = (U256::from(*sender_locked_amount) * U256::from(shares)
+                / U256::from(sender_subscription.shares))
+            .as_u128();
+            *sender_locked_amount -= locked_amount;
+            *receiver_locked_amount += locked_amount;
+        }
+        sender_subscription.shares -= shares;
+        sender_subscription.spent_in_balance_without_shares -= spent_in_balance;
+        sender_subscription.deposited_in_balance -= deposited_in_balance;
+        receiver_subscription.shares += shares;
+        receiver_subscription.spent_in_balance_without_shares += spent_in_balance;
+        receiver_subscription.deposited_in_balance += deposited_in_balance;
+
+        sender_subscription.last_in_balance = sale.shares_to_in_balance(sender_subscription.shares);
+        receiver_subscription.last_in_balance =
+            sale.shares_to_in_balance(receiver_subscription.shares);
+        sale.assert_no_dust(sender_subscription.last_in_balance);
+        sale.assert_no_dust(receiver_subscription.last_in_balance);
+        let max_in_amount = match (sale.max_in_per_account, receiver_subscription.allocation) {
+            (Some(max_in_per_account), Some(allocation)) => {
+                Some(std::cmp::min(max_in_per_account, allocation))
+            }
+            (max_in_per_account, allocation) => max_in_per_account.or(allocation),
+        };
+        if let Some(max_in_amount) = max_in_amount {
+            assert!(
+                receiver_subscription.deposited_in_balance <= max_in_amount,
+                "{}",
+                errors::MAX_IN_PER_ACCOUNT_REACHED
+            );
+        }
+
+        if moves_token {
+            // Nothing is left in the sender's subscription.
+            receiver_subscription.token_id = sender_subscription.token_id.take();
+            receiver_subscription.next_approval_id = sender_subscription.next_approval_id;
+            self.internal_remove_subscription(&mut sender, sale_id, &sender_subscription);
+        } else {
+            self.internal_save_subscription(
+                sender_id,
+                &mut sender,
+                sale_id,
+                &sale,
+                sender_subscription,
+            );
+        }
+        self.internal_save_subscription(
+            receiver_id,
+            &mut receiver,
+            sale_id,
+            &sale,
+            receiver_subscription,
+        );
+        self.accounts.insert(sender_id, &sender.into());
+        self.accounts.insert(receiver_id, &receiver.into());
+        self.sales.insert(&sale_id, &sale.into());
+    }
+
     pub fn internal_withdraw_in_token_exact(
         &mut self,
         sale_id: u64,
@@ -144,8 +363,9 @@ impl Contract {
         sale.in_token_remaining -= in_amount;
 
         subscription.last_in_balance = sale.shares_to_in_balance(subscription.shares);
+        sale.assert_no_dust(subscription.last_in_balance);
 
-        account.internal_save_subscription(sale_id, &sale, subscription);
+        self.internal_save_subscription(account_id, &mut account, sale_id, &sale, subscription);
         self.accounts.insert(&account_id, &account.into());
         self.sales.insert(&sale_id, &sale.into());
     }
@@ -156,22 +376,53 @@ impl Contract {
         account_id: &AccountId,
         in_amount: Balance,
         referral_id: Option<&AccountId>,
-        passed_permission_check: bool,
-    ) -> Option<AccountId> {
+        permission: DepositPermission,
+    ) -> Option<PermissionsCheck> {
         assert_ne!(referral_id, Some(account_id), "{}", errors::SELF_REFERRAL);
         assert!(in_amount > 0, "{}", errors::ZERO_IN_AMOUNT);
         let mut sale = self.internal_unwrap_sale(sale_id);
-        self.internal_distribute_unclaimed_tokens(&mut sale);
+        assert!(!sale.cancelled, "{}", errors::SALE_CANCELLED);
+        let (passed_permission_check, allocation, voucher_issued_at) = match permission {
+            DepositPermission::Unchecked => (false, None, None),
+            DepositPermission::Approved { allocation } => (true, allocation, None),
+            DepositPermission::Voucher(voucher) => {
+                let issued_at = voucher.issued_at.0;
+                (
+                    true,
+                    Some(sale.verify_voucher(sale_id, account_id, voucher)),
+                    Some(issued_at),
+                )
+            }
+        };
+        let in_amount = if let Some(in_token_headroom) = sale.in_token_headroom() {
+            // Accepting only the part of the deposit that fits under the maximum.
+            assert!(in_token_headroom > 0, "{}", errors::MAX_IN_TOKEN_REACHED);
+            std::cmp::min(in_amount, in_token_headroom)
+        } else {
+            in_amount
+        };
         let mut account = self.internal_unwrap_account(account_id);
         if !passed_permission_check {
             if let Some(permissions_contract_id) = &sale.permissions_contract_id {
-                if account.subs.get(&sale_id).is_none() {
+                // Subscriptions without shares are checked again, since the account might have
+                // been force-exited.
+                let has_shares = account
+                    .subs
+                    .get(&sale_id)
+                    .map(|subscription| Subscription::from(subscription).shares > 0)
+                    .unwrap_or(false);
+                if sale.recheck_permissions || !has_shares {
                     // Need to check permissions first
-                    return Some(permissions_contract_id.clone());
+                    return Some(PermissionsCheck {
+                        permissions_contract_id: permissions_contract_id.clone(),
+                        with_allocations: sale.permissions_with_allocations,
+                    });
                 }
             }
         }
 
+        // Distributing after the permissions check, since returning for it doesn't save the sale.
+        self.internal_distribute_unclaimed_tokens(&mut sale);
         let mut subscription = self.internal_update_subscription(
             &mut account,
             sale_id,
@@ -179,22 +430,64 @@ impl Contract {
             referral_id,
             passed_permission_check,
         );
+        if let Some(issued_at) = voucher_issued_at {
+            assert!(
+                subscription
+                    .voucher_issued_at
+                    .map(|last_issued_at| last_issued_at < issued_at)
+                    .unwrap_or(true),
+                "{}",
+                errors::OUTDATED_VOUCHER
+            );
+            subscription.voucher_issued_at = Some(issued_at);
+        }
+        if allocation.is_some() {
+            subscription.allocation = allocation;
+        }
+
+        let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
+        subscription.spent_in_balance_without_shares +=
+            subscription.last_in_balance - remaining_in_balance;
+        let deposited_in_balance = subscription.deposited_in_balance;
+        let max_in_amount = match (sale.max_in_per_account, subscription.allocation) {
+            (Some(max_in_per_account), Some(allocation)) => {
+                Some(std::cmp::min(max_in_per_account, allocation))
+            }
+            (max_in_per_account, allocation) => max_in_per_account.or(allocation),
+        };
+        let in_amount = if let Some(max_in_amount) = max_in_amount {
+            // Accepting only the part of the deposit that fits under the account maximum.
+            let available_in_amount = max_in_amount.saturating_sub(deposited_in_balance);
+            assert!(
+                available_in_amount > 0,
+                "{}",
+                errors::MAX_IN_PER_ACCOUNT_REACHED
+            );
+            std::cmp::min(in_amount, available_in_amount)
+        } else {
+            in_amount
+        };
+        if let Some(min_in_per_account) = sale.min_in_per_account {
+            assert!(
+                deposited_in_balance + in_amount >= min_in_per_account,
+                "{}",
+                errors::BELOW_MIN_IN_PER_ACCOUNT
+            );
+        }
 
         account.internal_token_withdraw(&sale.in_token_account_id, in_amount);
         for out_token in &sale.out_tokens {
             self.internal_maybe_register_token(&mut account, &out_token.token_account_id);
         }
-        let remaining_in_balance = sale.shares_to_in_balance(subscription.shares);
-        subscription.spent_in_balance_without_shares +=
-            subscription.last_in_balance - remaining_in_balance;
         let shares = sale.in_amount_to_shares(in_amount, false);
         subscription.shares += shares;
+        subscription.deposited_in_balance += in_amount;
         sale.total_shares += shares;
         sale.in_token_remaining += in_amount;
 
         subscription.last_in_balance = sale.shares_to_in_balance(subscription.shares);
 
-        account.internal_save_subscription(sale_id, &sale, subscription);
+        self.internal_save_subscription(account_id, &mut account, sale_id, &sale, subscription);
         self.accounts.insert(&account_id, &account.into());
         self.sales.insert(&sale_id, &sale.into());
         None
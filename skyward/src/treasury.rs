@@ -29,6 +29,17 @@ impl From<VestingIntervalInput> for VestingInterval {
     }
 }
 
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct OldTreasury {
+    pub balances: UnorderedMap<TokenAccountId, Balance>,
+    pub skyward_token_id: TokenAccountId,
+    pub skyward_burned_amount: Balance,
+    pub skyward_vesting_schedule: LazyOption<Vec<VestingInterval>>,
+    pub listing_fee_near: Balance,
+    pub w_near_token_id: TokenAccountId,
+    pub locked_attached_deposits: Balance,
+}
+
 #[derive(BorshDeserialize, BorshSerialize)]
 pub struct Treasury {
     pub balances: UnorderedMap<TokenAccountId, Balance>,
@@ -43,6 +54,24 @@ pub struct Treasury {
 
     // The amount of NEAR locked while the permissions are being verified.
     pub locked_attached_deposits: Balance,
+
+    // The total amount of NEAR deposited with `storage_deposit` that is not used for storage yet.
+    pub storage_balances: Balance,
+}
+
+impl From<OldTreasury> for Treasury {
+    fn from(old_treasury: OldTreasury) -> Self {
+        Self {
+            balances: old_treasury.balances,
+            skyward_token_id: old_treasury.skyward_token_id,
+            skyward_burned_amount: old_treasury.skyward_burned_amount,
+            skyward_vesting_schedule: old_treasury.skyward_vesting_schedule,
+            listing_fee_near: old_treasury.listing_fee_near,
+            w_near_token_id: old_treasury.w_near_token_id,
+            locked_attached_deposits: old_treasury.locked_attached_deposits,
+            storage_balances: 0,
+        }
+    }
 }
 
 impl Treasury {
@@ -69,6 +98,7 @@ impl Treasury {
             listing_fee_near,
             w_near_token_id,
             locked_attached_deposits: 0,
+            storage_balances: 0,
         }
     }
 
@@ -192,7 +222,8 @@ impl Contract {
     pub fn wrap_extra_near(&mut self) -> Promise {
         let unused_near_balance = env::account_balance()
             - Balance::from(env::storage_usage()) * env::storage_byte_cost()
-            - self.treasury.locked_attached_deposits;
+            - self.treasury.locked_attached_deposits
+            - self.treasury.storage_balances;
         assert!(
             unused_near_balance > MIN_EXTRA_NEAR,
             "{}",
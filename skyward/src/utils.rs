@@ -9,14 +9,27 @@ pub(crate) const STORAGE_DEPOSIT: Balance = 125 * env::STORAGE_PRICE_PER_BYTE;
 pub(crate) const EXTRA_NEAR_FOR_STORAGE: Balance = 1000 * env::STORAGE_PRICE_PER_BYTE;
 pub(crate) const EXTRA_NEAR: Balance = EXTRA_NEAR_FOR_STORAGE + STORAGE_DEPOSIT;
 pub(crate) const MIN_EXTRA_NEAR: Balance = EXTRA_NEAR + ONE_NEAR;
+/// The minimum deposit to register an account with `storage_deposit`.
+pub(crate) const ACCOUNT_STORAGE_DEPOSIT: Balance = 1000 * env::STORAGE_PRICE_PER_BYTE;
 
 const BASE_GAS: Gas = 5_000_000_000_000;
 pub(crate) const FT_TRANSFER_GAS: Gas = BASE_GAS;
 pub(crate) const AFTER_FT_TRANSFER_GAS: Gas = BASE_GAS;
+pub(crate) const FT_TRANSFER_CALL_GAS: Gas = BASE_GAS * 10;
+pub(crate) const AFTER_FT_TRANSFER_CALL_GAS: Gas = BASE_GAS;
 pub(crate) const AFTER_NEAR_DEPOSIT_GAS: Gas = BASE_GAS;
+pub(crate) const ON_SKYWARD_TRANSFER_GAS: Gas = BASE_GAS * 10;
+pub(crate) const AFTER_TRANSFER_CALL_GAS: Gas = BASE_GAS;
+pub(crate) const NFT_ON_TRANSFER_GAS: Gas = BASE_GAS * 10;
+pub(crate) const NFT_RESOLVE_TRANSFER_GAS: Gas = BASE_GAS * 6;
+pub(crate) const NFT_ON_APPROVE_GAS: Gas = BASE_GAS * 10;
 
 pub(crate) const STORAGE_DEPOSIT_GAS: Gas = BASE_GAS * 2;
 pub(crate) const NEAR_DEPOSIT_GAS: Gas = BASE_GAS;
+pub(crate) const NEAR_WITHDRAW_GAS: Gas = BASE_GAS;
+pub(crate) const AFTER_NEAR_WITHDRAW_GAS: Gas = BASE_GAS;
+pub(crate) const SALE_DEPOSIT_W_NEAR_GAS: Gas = BASE_GAS * 6;
+pub(crate) const AFTER_SALE_NEAR_DEPOSIT_GAS: Gas = BASE_GAS + SALE_DEPOSIT_W_NEAR_GAS;
 
 pub(crate) const PERMISSION_CONTRACT_GAS: Gas = BASE_GAS * 10;
 pub(crate) const AFTER_IS_APPROVED_GAS: Gas = BASE_GAS * 4;
@@ -36,25 +49,6 @@ uint::construct_uint! {
     pub struct U384(6);
 }
 
-pub(crate) fn refund_extra_storage_deposit(storage_used: StorageUsage, used_balance: Balance) {
-    let required_cost = env::storage_byte_cost() * Balance::from(storage_used);
-    let attached_deposit = env::attached_deposit()
-        .checked_sub(used_balance)
-        .expect(errors::NOT_ENOUGH_ATTACHED_BALANCE);
-
-    assert!(
-        required_cost <= attached_deposit,
-        "{} {}",
-        errors::NOT_ENOUGH_ATTACHED_BALANCE,
-        required_cost,
-    );
-
-    let refund = attached_deposit - required_cost;
-    if refund > 1 {
-        Promise::new(env::predecessor_account_id()).transfer(refund);
-    }
-}
-
 pub(crate) fn refund_released_storage(account_id: &AccountId, storage_released: StorageUsage) {
     if storage_released > 0 {
         let refund =
@@ -1,13 +1,19 @@
+use ed25519_dalek::Signer;
 use near_contract_standards::fungible_token::metadata::{FungibleTokenMetadata, FT_METADATA_SPEC};
-use near_sdk::json_types::{ValidAccountId, WrappedBalance, U128};
-use near_sdk::serde_json::json;
-use near_sdk::test_utils::accounts;
-use near_sdk::{env, AccountId, Balance, Gas, Timestamp};
+use near_sdk::borsh::BorshSerialize;
+use near_sdk::json_types::{
+    Base58PublicKey, ValidAccountId, WrappedBalance, WrappedTimestamp, U128,
+};
+use near_sdk::serde_json::{self, json, Value};
+use near_sdk::test_utils::{accounts, VMContextBuilder};
+use near_sdk::{env, testing_env, AccountId, Balance, Gas, Timestamp};
 use near_sdk_sim::runtime::GenesisConfig;
 use near_sdk_sim::{deploy, init_simulator, to_yocto, ContractAccount, UserAccount};
 use skyward::{
-    ContractContract as SkywardContract, SaleInput, SaleInputOutToken, SaleOutput,
-    SaleOutputOutToken, SubscriptionOutput, VestingIntervalInput,
+    whitelist_leaf_hash, whitelist_node_hash, ContractContract as SkywardContract, OperatorOutput,
+    SaleInput, SaleInputOutToken, SaleOutput, SaleOutputOutToken, SalePrice, SaleVoucher,
+    SubscriptionOutput, VestingIntervalInput, VoucherMessage, WhitelistProof,
+    VOUCHER_MESSAGE_PREFIX,
 };
 use std::convert::TryInto;
 
@@ -309,9 +315,53 @@ impl Env {
         permissions_contract_id: Option<ValidAccountId>,
         referral_bpt: Option<u16>,
     ) -> SaleOutput {
+        let mut sale = self.sale_input(user, tokens, start_offset, sale_duration);
+        sale.permissions_contract_id = permissions_contract_id;
+        for out_token in &mut sale.out_tokens {
+            out_token.referral_bpt = referral_bpt;
+        }
+        self.sale_create_from_input(user, sale)
+    }
+
+    pub fn sale_input(
+        &self,
+        user: &UserAccount,
+        tokens: &[(&UserAccount, Balance)],
+        start_offset: u64,
+        sale_duration: u64,
+    ) -> SaleInput {
         let current_time = user.borrow_runtime().current_block().block_timestamp;
         let start_time = current_time + start_offset;
 
+        SaleInput {
+            title: TITLE.to_string(),
+            url: None,
+            permissions_contract_id: None,
+            out_tokens: tokens
+                .iter()
+                .map(|(token, balance)| SaleInputOutToken {
+                    token_account_id: token.valid_account_id(),
+                    balance: (*balance).into(),
+                    referral_bpt: None,
+                    min_price: None,
+                })
+                .collect(),
+            in_token_account_id: self.w_near.valid_account_id(),
+            start_time: start_time.into(),
+            duration: sale_duration.into(),
+            min_in_token_paid: None,
+            max_in_token: None,
+            max_in_per_account: None,
+            min_in_per_account: None,
+            permissions_with_allocations: None,
+            whitelist_root: None,
+            whitelist_with_allocations: None,
+            voucher_public_key: None,
+            recheck_permissions: None,
+        }
+    }
+
+    pub fn sale_create_from_input(&self, user: &UserAccount, sale: SaleInput) -> SaleOutput {
         let initial_balance = user.account().unwrap().amount;
 
         let deposit = if user.account_id != SKYWARD_ID {
@@ -319,26 +369,7 @@ impl Env {
         } else {
             0
         };
-        let res = user.function_call(
-            self.skyward.contract.sale_create(SaleInput {
-                title: TITLE.to_string(),
-                url: None,
-                permissions_contract_id,
-                out_tokens: tokens
-                    .iter()
-                    .map(|(token, balance)| SaleInputOutToken {
-                        token_account_id: token.valid_account_id(),
-                        balance: (*balance).into(),
-                        referral_bpt,
-                    })
-                    .collect(),
-                in_token_account_id: self.w_near.valid_account_id(),
-                start_time: start_time.into(),
-                duration: sale_duration.into(),
-            }),
-            BASE_GAS,
-            deposit,
-        );
+        let res = user.function_call(self.skyward.contract.sale_create(sale), BASE_GAS, deposit);
         res.assert_success();
 
         let balance_spent = initial_balance - user.account().unwrap().amount;
@@ -611,7 +642,8 @@ fn test_create_sale() {
                 remaining: to_yocto("4000").into(),
                 distributed: 0.into(),
                 treasury_unclaimed: Some(0.into()),
-                referral_bpt: None
+                referral_bpt: None,
+                min_price: None,
             }],
             in_token_account_id: e.w_near.account_id.clone(),
             in_token_remaining: U128(0),
@@ -625,7 +657,19 @@ fn test_create_sale() {
             current_time: to_nano(GENESIS_TIME).into(),
             current_block_height: alice.borrow_runtime().current_block().block_height,
             start_block_height: sale.start_block_height,
-            end_block_height: None
+            end_block_height: None,
+            cancelled: false,
+            min_in_token_paid: None,
+            failed: false,
+            max_in_token: None,
+            in_token_headroom: None,
+            max_in_per_account: None,
+            min_in_per_account: None,
+            permissions_with_allocations: false,
+            whitelist_root: None,
+            whitelist_with_allocations: false,
+            voucher_public_key: None,
+            recheck_permissions: false,
         },
     );
 
@@ -650,9 +694,14 @@ fn test_join_sale() {
     let sale = e.sale_create(alice, &[(&token1, to_yocto("3600"))]);
 
     bob.function_call(
-        e.skyward
-            .contract
-            .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
         BASE_GAS,
         to_yocto("0.01"),
     )
@@ -669,7 +718,8 @@ fn test_join_sale() {
             remaining_in_balance: to_yocto("4").into(),
             unclaimed_out_balances: vec![U128(0)],
             shares: to_yocto("4").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         })
     );
 
@@ -703,6 +753,7 @@ fn test_join_sale() {
                 unclaimed_out_balances: vec![to_yocto("1782").into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -730,6 +781,7 @@ fn test_join_sale() {
                 unclaimed_out_balances: vec![to_yocto("3564").into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -793,12 +845,17 @@ fn test_join_sale() {
                 unclaimed_out_balances: vec![to_yocto("3564").into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     let bobs_sale = e.get_sale(0, Some(bob.valid_account_id()));
 
@@ -860,6 +917,9 @@ fn test_join_sale_with_referral() {
             sale.sale_id,
             to_yocto("4").into(),
             Some(alice.valid_account_id()),
+            None,
+            None,
+            None,
         ),
         BASE_GAS,
         to_yocto("0.01"),
@@ -886,6 +946,7 @@ fn test_join_sale_with_referral() {
                 unclaimed_out_balances: vec![U128(0)],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -927,6 +988,7 @@ fn test_join_sale_with_referral() {
                 unclaimed_out_balances: vec![(sale_amount / 2).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -953,6 +1015,7 @@ fn test_join_sale_with_referral() {
                 unclaimed_out_balances: vec![sale_amount.into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1017,12 +1080,17 @@ fn test_join_sale_with_referral() {
                 unclaimed_out_balances: vec![sale_amount.into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     let bobs_sale = e.get_sale(0, Some(bob.valid_account_id()));
     e.assert_sale_eq(
@@ -1090,6 +1158,9 @@ fn test_join_sale_with_referral_and_alice() {
             sale.sale_id,
             to_yocto("4").into(),
             Some(alice.valid_account_id()),
+            None,
+            None,
+            None,
         ),
         BASE_GAS,
         to_yocto("0.01"),
@@ -1098,9 +1169,14 @@ fn test_join_sale_with_referral_and_alice() {
 
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("1").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -1126,6 +1202,7 @@ fn test_join_sale_with_referral_and_alice() {
                 unclaimed_out_balances: vec![U128(0)],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1138,7 +1215,8 @@ fn test_join_sale_with_referral_and_alice() {
             remaining_in_balance: to_yocto("1").into(),
             unclaimed_out_balances: vec![U128(0)],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
@@ -1179,6 +1257,7 @@ fn test_join_sale_with_referral_and_alice() {
                 unclaimed_out_balances: vec![(sale_amount / 5 * 4 / 2).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1191,7 +1270,8 @@ fn test_join_sale_with_referral_and_alice() {
             remaining_in_balance: to_yocto("0.5").into(),
             unclaimed_out_balances: vec![(sale_amount / 5 * 1 / 2).into()],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
@@ -1217,6 +1297,7 @@ fn test_join_sale_with_referral_and_alice() {
                 unclaimed_out_balances: vec![(sale_amount / 5 * 4).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1229,7 +1310,8 @@ fn test_join_sale_with_referral_and_alice() {
             remaining_in_balance: 0.into(),
             unclaimed_out_balances: vec![(sale_amount / 5).into()],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
@@ -1294,6 +1376,7 @@ fn test_join_sale_with_referral_and_alice() {
                 unclaimed_out_balances: vec![(sale_amount / 5 * 4).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1306,14 +1389,19 @@ fn test_join_sale_with_referral_and_alice() {
             remaining_in_balance: 0.into(),
             unclaimed_out_balances: vec![(sale_amount / 5).into()],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
     assert_eq!(e.skyward_circulating_supply(), SKYWARD_TOTAL_SUPPLY);
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     let bobs_sale = e.get_sale(0, Some(bob.valid_account_id()));
     e.assert_sale_eq(
@@ -1340,7 +1428,8 @@ fn test_join_sale_with_referral_and_alice() {
             remaining_in_balance: 0.into(),
             unclaimed_out_balances: vec![(sale_amount / 5).into()],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
@@ -1371,7 +1460,11 @@ fn test_join_sale_with_referral_and_alice() {
     );
 
     alice
-        .function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
+        .function_call(
+            e.skyward.contract.sale_claim_out_tokens(0, None),
+            BASE_GAS,
+            0,
+        )
         .assert_success();
 
     let alice_sale = e.get_sale(0, Some(alice.valid_account_id()));
@@ -1453,6 +1546,9 @@ fn test_join_sale_and_leave() {
             sale.sale_id,
             to_yocto("4").into(),
             Some(alice.valid_account_id()),
+            None,
+            None,
+            None,
         ),
         BASE_GAS,
         to_yocto("0.01"),
@@ -1461,9 +1557,14 @@ fn test_join_sale_and_leave() {
 
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("1").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -1489,6 +1590,7 @@ fn test_join_sale_and_leave() {
                 unclaimed_out_balances: vec![U128(0)],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1501,7 +1603,8 @@ fn test_join_sale_and_leave() {
             remaining_in_balance: to_yocto("1").into(),
             unclaimed_out_balances: vec![U128(0)],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
@@ -1545,6 +1648,7 @@ fn test_join_sale_and_leave() {
                 unclaimed_out_balances: vec![(sale_amount / 5 * 4 / 2).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1557,14 +1661,15 @@ fn test_join_sale_and_leave() {
             remaining_in_balance: to_yocto("0.5").into(),
             unclaimed_out_balances: vec![(sale_amount / 5 * 1 / 2).into()],
             shares: to_yocto("1").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         }),
     );
 
     // Alice leaves sale
     alice
         .function_call(
-            e.skyward.contract.sale_withdraw_in_token(0, None),
+            e.skyward.contract.sale_withdraw_in_token(0, None, None),
             BASE_GAS,
             1,
         )
@@ -1608,6 +1713,7 @@ fn test_join_sale_and_leave() {
                 unclaimed_out_balances: vec![(sale_amount * 9 / 10).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
@@ -1680,12 +1786,17 @@ fn test_join_sale_and_leave() {
                 unclaimed_out_balances: vec![(sale_amount * 9 / 10).into()],
                 shares: to_yocto("4").into(),
                 referral_id: Some(alice.account_id.clone()),
+                max_price: None,
             }),
         },
     );
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     let bobs_sale = e.get_sale(0, Some(bob.valid_account_id()));
     e.assert_sale_eq(
@@ -1765,9 +1876,14 @@ fn test_join_sale_and_withdraw_exact() {
 
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -1784,9 +1900,11 @@ fn test_join_sale_and_withdraw_exact() {
     );
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_withdraw_in_token_exact(sale.sale_id, to_yocto("2").into()),
+            e.skyward.contract.sale_withdraw_in_token_exact(
+                sale.sale_id,
+                to_yocto("2").into(),
+                None,
+            ),
             BASE_GAS,
             1,
         )
@@ -1829,9 +1947,14 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
     assert_eq!(e.skyward_circulating_supply(), 0);
 
     bob.function_call(
-        e.skyward
-            .contract
-            .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
         BASE_GAS,
         to_yocto("0.01"),
     )
@@ -1857,6 +1980,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![U128(0)],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -1891,14 +2015,19 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![(sale_amount / 4).into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
 
     assert_eq!(e.skyward_circulating_supply(), sale_amount / 4);
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     assert_eq!(
         e.skyward_circulating_supply(),
@@ -1925,6 +2054,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![0.into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -1956,6 +2086,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![(sale_amount / 4).into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -1967,9 +2098,14 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
 
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("3").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("3").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -1995,6 +2131,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![0.into()],
                 shares: to_yocto("6").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -2045,6 +2182,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![(sale_amount * 7 / 20).into()],
                 shares: to_yocto("4").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -2052,7 +2190,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
     bob.function_call(
         e.skyward
             .contract
-            .sale_withdraw_in_token(0, Some(to_yocto("2").into())),
+            .sale_withdraw_in_token(0, Some(to_yocto("2").into()), None),
         BASE_GAS,
         1,
     )
@@ -2078,6 +2216,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![0.into()],
                 shares: to_yocto("2").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -2115,6 +2254,7 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![(sale_amount * 1 / 16).into()],
                 shares: to_yocto("2").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
@@ -2128,7 +2268,8 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
             remaining_in_balance: to_yocto("0").into(),
             unclaimed_out_balances: vec![(sale_amount * 27 / 80).into()],
             shares: to_yocto("6").into(),
-            referral_id: None
+            referral_id: None,
+            max_price: None,
         })
     );
 
@@ -2143,7 +2284,11 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
     );
 
     alice
-        .function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
+        .function_call(
+            e.skyward.contract.sale_claim_out_tokens(0, None),
+            BASE_GAS,
+            0,
+        )
         .assert_success();
 
     assert_eq!(
@@ -2198,12 +2343,17 @@ fn test_skyward_sale_alice_joins_in_the_middle() {
                 unclaimed_out_balances: vec![(sale_amount * 1 / 16).into()],
                 shares: to_yocto("2").into(),
                 referral_id: None,
+                max_price: None,
             }),
         },
     );
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     let bobs_sale = e.get_sale(0, Some(bob.valid_account_id()));
     e.assert_sale_eq(
@@ -2282,6 +2432,9 @@ fn test_skyward_sale_ref() {
             sale.sale_id,
             to_yocto("4").into(),
             Some(alice.valid_account_id()),
+            None,
+            None,
+            None,
         ),
         BASE_GAS,
         to_yocto("0.01"),
@@ -2294,6 +2447,9 @@ fn test_skyward_sale_ref() {
                 sale.sale_id,
                 to_yocto("4").into(),
                 Some(e.near.valid_account_id()),
+                None,
+                None,
+                None,
             ),
             BASE_GAS,
             to_yocto("0.01"),
@@ -2302,9 +2458,14 @@ fn test_skyward_sale_ref() {
 
     danny
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("8").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("8").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -2312,15 +2473,27 @@ fn test_skyward_sale_ref() {
 
     e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     charlie
-        .function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
+        .function_call(
+            e.skyward.contract.sale_claim_out_tokens(0, None),
+            BASE_GAS,
+            0,
+        )
         .assert_success();
 
     danny
-        .function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
+        .function_call(
+            e.skyward.contract.sale_claim_out_tokens(0, None),
+            BASE_GAS,
+            0,
+        )
         .assert_success();
 
     assert_eq!(
@@ -2509,9 +2682,14 @@ fn test_skyward_sale_half_empty() {
 
     alice
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -2521,7 +2699,7 @@ fn test_skyward_sale_half_empty() {
 
     alice
         .function_call(
-            e.skyward.contract.sale_withdraw_in_token(0, None),
+            e.skyward.contract.sale_withdraw_in_token(0, None, None),
             BASE_GAS,
             1,
         )
@@ -2579,9 +2757,14 @@ fn test_regular_sale_half_empty() {
     assert_eq!(e.skyward_circulating_supply(), 0);
 
     bob.function_call(
-        e.skyward
-            .contract
-            .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
         BASE_GAS,
         to_yocto("0.01"),
     )
@@ -2590,7 +2773,7 @@ fn test_regular_sale_half_empty() {
     e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
 
     bob.function_call(
-        e.skyward.contract.sale_withdraw_in_token(0, None),
+        e.skyward.contract.sale_withdraw_in_token(0, None, None),
         BASE_GAS,
         1,
     )
@@ -2657,9 +2840,14 @@ fn test_regular_sale_join_in_the_middle() {
     e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
 
     bob.function_call(
-        e.skyward
-            .contract
-            .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
         BASE_GAS,
         to_yocto("0.01"),
     )
@@ -2667,8 +2855,12 @@ fn test_regular_sale_join_in_the_middle() {
 
     e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     assert_eq!(
         e.balances_of(bob),
@@ -2708,9 +2900,14 @@ fn test_permissions_sale() {
 
     assert!(!bob
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None
+            ),
             BASE_GAS,
             to_yocto("0.01"),
         )
@@ -2719,9 +2916,14 @@ fn test_permissions_sale() {
     let initial_balance = e.skyward.user_account.account().unwrap().amount;
     let result: bool = bob
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             TON_OF_GAS,
             to_yocto("1"),
         )
@@ -2740,16 +2942,21 @@ fn test_permissions_sale() {
             .to_string()
             .into_bytes(),
             BASE_GAS,
-            0,
+            to_yocto("0.01"),
         )
         .assert_success();
 
     let initial_balance = e.skyward.user_account.account().unwrap().amount;
     let result: bool = bob
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             TON_OF_GAS,
             to_yocto("1"),
         )
@@ -2760,9 +2967,14 @@ fn test_permissions_sale() {
 
     // Already approved, so don't need ton of gas.
     bob.function_call(
-        e.skyward
-            .contract
-            .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
         BASE_GAS,
         to_yocto("0.01"),
     )
@@ -2770,8 +2982,12 @@ fn test_permissions_sale() {
 
     e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
 
-    bob.function_call(e.skyward.contract.sale_claim_out_tokens(0), BASE_GAS, 0)
-        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(0, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
 
     assert_eq!(
         e.balances_of(bob),
@@ -2803,20 +3019,30 @@ fn test_invalid_permissions_sale() {
 
     assert!(!bob
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
-            BASE_GAS,
-            to_yocto("0.01"),
-        )
-        .is_ok());
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
 
     let initial_balance = e.skyward.user_account.account().unwrap().amount;
     let result: bool = bob
         .function_call(
-            e.skyward
-                .contract
-                .sale_deposit_in_token(sale.sale_id, to_yocto("4").into(), None),
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
             TON_OF_GAS,
             to_yocto("1"),
         )
@@ -2843,3 +3069,2825 @@ fn test_invalid_permissions_sale() {
         ]
     );
 }
+
+#[test]
+fn test_sale_cancel() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    // Only the owner can cancel the sale.
+    assert!(!bob
+        .function_call(e.skyward.contract.sale_cancel(sale.sale_id), BASE_GAS, 1)
+        .is_ok());
+
+    alice
+        .function_call(e.skyward.contract.sale_cancel(sale.sale_id), BASE_GAS, 1)
+        .assert_success();
+
+    let sale = e.get_sale(sale.sale_id, None);
+    assert!(sale.cancelled);
+    assert_eq!(sale.out_tokens[0].remaining.0, 0);
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), to_yocto("10000")),
+        ]
+    );
+
+    // Can't join a cancelled sale.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // The cancelled sale doesn't progress after the start time.
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert_eq!(bobs_sale.in_token_remaining.0, to_yocto("4"));
+    assert_eq!(bobs_sale.in_token_paid.0, 0);
+
+    bob.function_call(
+        e.skyward
+            .contract
+            .sale_withdraw_in_token(sale.sale_id, None, None),
+        BASE_GAS,
+        1,
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+}
+
+#[test]
+fn test_sale_min_in_token_paid() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    // The first sale doesn't reach the minimum.
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.min_in_token_paid = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+
+    // Proceeds and out tokens are locked while the sale is in progress.
+    alice
+        .function_call(
+            e.skyward
+                .contract
+                .sale_distribute_unclaimed_tokens(sale.sale_id),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), to_yocto("6000")),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("6")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert!(bobs_sale.failed);
+
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), to_yocto("10000")),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+    assert_eq!(
+        e.get_sale(sale.sale_id, Some(bob.valid_account_id()))
+            .subscription,
+        None
+    );
+
+    // The second sale reaches the minimum.
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.min_in_token_paid = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    for user in &[bob, charlie] {
+        user.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    }
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert!(!bobs_sale.failed);
+
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("17.92")),
+            (token1.account_id.clone(), to_yocto("6000")),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("6")),
+            (token1.account_id.clone(), to_yocto("1980")),
+        ]
+    );
+}
+
+#[test]
+fn test_sale_min_in_token_paid_full_withdraw() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let deposit = |user: &UserAccount, sale_id: u64| {
+        user.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    };
+    let withdraw_all = |user: &UserAccount, sale_id: u64| {
+        user.function_call(
+            e.skyward
+                .contract
+                .sale_withdraw_in_token(sale_id, None, None),
+            BASE_GAS,
+            1,
+        )
+        .assert_success();
+    };
+    let claim = |user: &UserAccount, sale_id: u64| {
+        user.function_call(
+            e.skyward.contract.sale_claim_out_tokens(sale_id, None),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+    };
+
+    // The first sale doesn't reach the minimum.
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.min_in_token_paid = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+    deposit(bob, sale.sale_id);
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+
+    // Withdrawing everything keeps the subscription with the spent in tokens.
+    withdraw_all(bob, sale.sale_id);
+    let subscription = e
+        .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+        .subscription
+        .unwrap();
+    assert_eq!(subscription.shares.0, 0);
+    assert_eq!(subscription.spent_in_balance.0, to_yocto("2"));
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    claim(bob, sale.sale_id);
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+    assert_eq!(
+        e.get_sale(sale.sale_id, Some(bob.valid_account_id()))
+            .subscription,
+        None
+    );
+
+    // The second sale reaches the minimum.
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.min_in_token_paid = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+    deposit(bob, sale.sale_id);
+    deposit(charlie, sale.sale_id);
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+
+    withdraw_all(bob, sale.sale_id);
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert!(!bobs_sale.failed);
+    claim(bob, sale.sale_id);
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("8")),
+            (token1.account_id.clone(), to_yocto("990")),
+        ]
+    );
+}
+
+#[test]
+fn test_sale_min_price() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("3600"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    // 1 in token for 100 out tokens.
+    sale_input.out_tokens[0].min_price = Some(SalePrice {
+        in_amount: to_yocto("1").into(),
+        out_amount: to_yocto("100").into(),
+    });
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    // Only 400 out tokens are released for 4 in tokens.
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    e.assert_sale_eq(
+        &bobs_sale,
+        PartialSale {
+            out_tokens: vec![PartialOutToken {
+                remaining: to_yocto("3200").into(),
+                distributed: to_yocto("400").into(),
+                treasury_unclaimed: Some(to_yocto("4").into()),
+            }],
+            in_token_remaining: to_yocto("0").into(),
+            in_token_paid_unclaimed: to_yocto("4").into(),
+            in_token_paid: to_yocto("4").into(),
+            total_shares: to_yocto("4").into(),
+            subscription: Some(SubscriptionOutput {
+                claimed_out_balance: vec![to_yocto("0").into()],
+                spent_in_balance: to_yocto("4").into(),
+                remaining_in_balance: to_yocto("0").into(),
+                unclaimed_out_balances: vec![to_yocto("396").into()],
+                shares: to_yocto("4").into(),
+                referral_id: None,
+                max_price: None,
+            }),
+        },
+    );
+
+    bob.function_call(
+        e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+        BASE_GAS,
+        0,
+    )
+    .assert_success();
+
+    // The unreleased out tokens are returned to the owner.
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("13.96")),
+            (token1.account_id.clone(), to_yocto("9600")),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("6")),
+            (token1.account_id.clone(), to_yocto("396")),
+        ]
+    );
+}
+
+#[test]
+fn test_sale_max_in_token() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("3600"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.max_in_token = Some(to_yocto("6").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+    assert_eq!(sale.in_token_headroom, Some(to_yocto("6").into()));
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    // Only 2 out of 4 are accepted.
+    charlie
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+    let charlies_sale = e.get_sale(sale.sale_id, Some(charlie.valid_account_id()));
+    assert_eq!(charlies_sale.in_token_remaining.0, to_yocto("6"));
+    assert_eq!(charlies_sale.in_token_headroom, Some(0.into()));
+    assert_eq!(
+        charlies_sale.subscription.unwrap().remaining_in_balance.0,
+        to_yocto("2")
+    );
+    assert_eq!(
+        e.balances_of(charlie),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("8")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    // The sale is full.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Withdrawing frees up the headroom.
+    bob.function_call(
+        e.skyward
+            .contract
+            .sale_withdraw_in_token_exact(sale.sale_id, to_yocto("1").into(), None),
+        BASE_GAS,
+        1,
+    )
+    .assert_success();
+
+    let sale = e.get_sale(sale.sale_id, None);
+    assert_eq!(sale.in_token_headroom, Some(to_yocto("1").into()));
+}
+
+#[test]
+fn test_sale_in_per_account_limits() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("3600"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.min_in_per_account = Some(to_yocto("1").into());
+    sale_input.max_in_per_account = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    // Below the minimum.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("0.5").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    // Only 1 out of 4 is accepted.
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("5")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Can't leave less than the minimum.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_withdraw_in_token_exact(
+                sale.sale_id,
+                to_yocto("4.5").into(),
+                None
+            ),
+            BASE_GAS,
+            1,
+        )
+        .is_ok());
+
+    bob.function_call(
+        e.skyward
+            .contract
+            .sale_withdraw_in_token_exact(sale.sale_id, to_yocto("4").into(), None),
+        BASE_GAS,
+        1,
+    )
+    .assert_success();
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert_eq!(
+        bobs_sale.subscription.unwrap().remaining_in_balance.0,
+        to_yocto("1")
+    );
+
+    // Withdrawals don't restore the account maximum.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+}
+
+#[test]
+fn test_permissions_sale_with_allocations() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.permissions_contract_id = Some(e.permissions_contract.valid_account_id());
+    sale_input.permissions_with_allocations = Some(true);
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    // No allocation yet.
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(!result);
+
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "set_allocation",
+            &json!({
+                "account_id": bob.valid_account_id(),
+                "sale_id": sale.sale_id,
+                "allocation": U128::from(to_yocto("3")),
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    let get_allocation = || -> Option<WrappedBalance> {
+        e.near
+            .view(
+                e.permissions_contract.account_id.clone(),
+                "get_allocation",
+                &json!({
+                    "account_id": bob.valid_account_id(),
+                    "sale_id": sale.sale_id,
+                })
+                .to_string()
+                .into_bytes(),
+            )
+            .unwrap_json()
+    };
+    // The allocation only applies to approved accounts.
+    assert_eq!(get_allocation(), None);
+
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "approve_for_sale",
+            &json!({
+                "account_id": bob.valid_account_id(),
+                "sale_id": sale.sale_id,
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    assert_eq!(get_allocation(), Some(to_yocto("3").into()));
+
+    // Only 3 out of 4 are accepted.
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(result);
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("7")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    // The allocation is used up.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Rejected accounts have no allocation.
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "reject_for_sale",
+            &json!({
+                "account_id": bob.valid_account_id(),
+                "sale_id": sale.sale_id,
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+    assert_eq!(get_allocation(), None);
+}
+
+#[test]
+fn test_permissions_sale_approved_for_sale() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sales: Vec<SaleOutput> = (0..2)
+        .map(|_| {
+            e.sale_create_custom(
+                alice,
+                &[(&token1, to_yocto("4000"))],
+                to_nano(WEEK) + BLOCK_DURATION * 15,
+                BLOCK_DURATION * 60,
+                Some(e.permissions_contract.valid_account_id()),
+                None,
+            )
+        })
+        .collect();
+
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "approve_for_sale",
+            &json!({
+                "account_id": bob.valid_account_id(),
+                "sale_id": sales[1].sale_id,
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sales[0].sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(!result);
+
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sales[1].sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(result);
+}
+
+#[test]
+fn test_permissions_approvers() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale = e.sale_create_custom(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+        Some(e.permissions_contract.valid_account_id()),
+        None,
+    );
+
+    let permissions_call = |user: &UserAccount, method: &str, args: Value| {
+        // Approvals and allocations pay for their storage.
+        let deposit = if method.starts_with("approve") || method == "set_allocation" {
+            to_yocto("0.01")
+        } else {
+            0
+        };
+        user.call(
+            e.permissions_contract.account_id.clone(),
+            method,
+            &args.to_string().into_bytes(),
+            BASE_GAS,
+            deposit,
+        )
+    };
+
+    // Only approvers can approve.
+    assert!(!permissions_call(
+        charlie,
+        "approve",
+        json!({ "account_id": bob.valid_account_id() })
+    )
+    .is_ok());
+
+    permissions_call(
+        &e.skyward_dao,
+        "add_approver",
+        json!({ "account_id": charlie.valid_account_id() }),
+    )
+    .assert_success();
+
+    permissions_call(
+        charlie,
+        "approve",
+        json!({ "account_id": bob.valid_account_id() }),
+    )
+    .assert_success();
+
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(result);
+
+    // Approvers can only approve and reject.
+    assert!(!permissions_call(
+        charlie,
+        "add_approver",
+        json!({ "account_id": alice.valid_account_id() })
+    )
+    .is_ok());
+    assert!(!permissions_call(
+        charlie,
+        "approve_for_sale",
+        json!({ "account_id": alice.valid_account_id(), "sale_id": sale.sale_id })
+    )
+    .is_ok());
+    assert!(!permissions_call(
+        charlie,
+        "set_allocation",
+        json!({
+            "account_id": bob.valid_account_id(),
+            "allocation": U128::from(to_yocto("100")),
+        })
+    )
+    .is_ok());
+
+    // Two-step ownership transfer.
+    permissions_call(
+        &e.skyward_dao,
+        "propose_owner",
+        json!({ "owner_id": charlie.valid_account_id() }),
+    )
+    .assert_success();
+    assert!(!permissions_call(alice, "accept_ownership", json!({})).is_ok());
+    permissions_call(charlie, "accept_ownership", json!({})).assert_success();
+
+    let owner_id: String = e
+        .near
+        .view(
+            e.permissions_contract.account_id.clone(),
+            "get_owner_id",
+            b"{}",
+        )
+        .unwrap_json();
+    assert_eq!(owner_id, charlie.account_id);
+
+    permissions_call(
+        charlie,
+        "add_approver",
+        json!({ "account_id": alice.valid_account_id() }),
+    )
+    .assert_success();
+}
+
+#[test]
+fn test_permissions_approve_many() {
+    let e = Env::init(3);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let approve_many = |deposit: Balance| {
+        e.skyward_dao.call(
+            e.permissions_contract.account_id.clone(),
+            "approve_many",
+            &json!({
+                "account_ids": [alice.valid_account_id(), bob.valid_account_id()]
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            deposit,
+        )
+    };
+    let view = |method: &str, args: Value| {
+        e.near.view(
+            e.permissions_contract.account_id.clone(),
+            method,
+            &args.to_string().into_bytes(),
+        )
+    };
+
+    // The storage of new approvals has to be paid for.
+    assert!(!approve_many(0).is_ok());
+    approve_many(to_yocto("1")).assert_success();
+
+    let num_approved: u64 = view("get_num_approved", json!({})).unwrap_json();
+    assert_eq!(num_approved, 2);
+
+    let approved: Vec<String> = view("get_approved_accounts", json!({})).unwrap_json();
+    assert_eq!(
+        approved,
+        vec![alice.account_id.clone(), bob.account_id.clone()]
+    );
+
+    let approved: Vec<String> = view(
+        "get_approved_accounts",
+        json!({ "from_index": 1, "limit": 5 }),
+    )
+    .unwrap_json();
+    assert_eq!(approved, vec![bob.account_id.clone()]);
+
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "reject_many",
+            &json!({
+                "account_ids": [alice.valid_account_id(), charlie.valid_account_id()]
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+
+    let approved: Vec<String> = view("get_approved_accounts", json!({})).unwrap_json();
+    assert_eq!(approved, vec![bob.account_id.clone()]);
+
+    // Approvals for a single sale have to be paid for as well.
+    let approve_many_for_sale = |deposit: Balance| {
+        e.skyward_dao.call(
+            e.permissions_contract.account_id.clone(),
+            "approve_many_for_sale",
+            &json!({
+                "account_ids": [alice.valid_account_id(), charlie.valid_account_id()],
+                "sale_id": 0,
+            })
+            .to_string()
+            .into_bytes(),
+            BASE_GAS,
+            deposit,
+        )
+    };
+    assert!(!approve_many_for_sale(0).is_ok());
+    approve_many_for_sale(to_yocto("1")).assert_success();
+    let is_approved: bool = view(
+        "is_approved",
+        json!({ "account_id": charlie.valid_account_id(), "sale_id": 0 }),
+    )
+    .unwrap_json();
+    assert!(is_approved);
+}
+
+#[test]
+fn test_permissions_expiring_approvals() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale = e.sale_create_custom(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+        Some(e.permissions_contract.valid_account_id()),
+        None,
+    );
+
+    let current_time = e.near.borrow_runtime().current_block().block_timestamp;
+    let approve = |user: &UserAccount, expires_at: Timestamp| {
+        e.skyward_dao
+            .call(
+                e.permissions_contract.account_id.clone(),
+                "approve",
+                &json!({
+                    "account_id": user.valid_account_id(),
+                    "expires_at": WrappedTimestamp::from(expires_at),
+                })
+                .to_string()
+                .into_bytes(),
+                BASE_GAS,
+                to_yocto("0.01"),
+            )
+            .assert_success();
+    };
+    approve(bob, sale.start_time.0);
+    approve(charlie, current_time - 1);
+
+    let expiring: Vec<(String, WrappedTimestamp)> = e
+        .near
+        .view(
+            e.permissions_contract.account_id.clone(),
+            "get_expiring_approvals",
+            &json!({ "before": WrappedTimestamp::from(sale.start_time.0 + 1) })
+                .to_string()
+                .into_bytes(),
+        )
+        .unwrap_json();
+    assert_eq!(
+        expiring,
+        vec![
+            (bob.account_id.clone(), sale.start_time),
+            (charlie.account_id.clone(), (current_time - 1).into()),
+        ]
+    );
+
+    let result: bool = bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(result);
+
+    // The approval has already expired.
+    let result: bool = charlie
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json();
+    assert!(!result);
+}
+
+#[test]
+fn test_sale_whitelist() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    // Hashing requires the mocked blockchain.
+    testing_env!(VMContextBuilder::new().build());
+    let bob_leaf = whitelist_leaf_hash(&bob.account_id, Some(to_yocto("3")));
+    let charlie_leaf = whitelist_leaf_hash(&charlie.account_id, Some(to_yocto("5")));
+    let root = whitelist_node_hash(&bob_leaf, &charlie_leaf);
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.whitelist_root = Some(root.into());
+    sale_input.whitelist_with_allocations = Some(true);
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    // Not whitelisted.
+    assert!(!charlie
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Wrong allocation.
+    assert!(!bob
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                Some(WhitelistProof {
+                    proof: vec![charlie_leaf.into()],
+                    allocation: Some(to_yocto("5").into()),
+                }),
+                None,
+                None
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Only 3 out of 4 are accepted.
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            Some(WhitelistProof {
+                proof: vec![charlie_leaf.into()],
+                allocation: Some(to_yocto("3").into()),
+            }),
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("7")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    charlie
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                Some(WhitelistProof {
+                    proof: vec![bob_leaf.into()],
+                    allocation: Some(to_yocto("5").into()),
+                }),
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+    // The account was approved by the first deposit.
+    charlie
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("1").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+    let sale = e.get_sale(sale.sale_id, None);
+    assert_eq!(sale.in_token_remaining.0, to_yocto("8"));
+}
+
+#[test]
+fn test_sale_vouchers() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    let keypair = ed25519_dalek::Keypair { secret, public };
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    let mut voucher_public_key = vec![0u8];
+    voucher_public_key.extend_from_slice(public.as_bytes());
+    sale_input.voucher_public_key = Some(Base58PublicKey(voucher_public_key));
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    let current_time = e.near.borrow_runtime().current_block().block_timestamp;
+    let sign_voucher = |contract_id: &str,
+                        account_id: &AccountId,
+                        allocation: Balance,
+                        expires_at: Timestamp,
+                        issued_at: Timestamp| {
+        let message = VoucherMessage {
+            prefix: VOUCHER_MESSAGE_PREFIX.to_string(),
+            contract_id: contract_id.to_string(),
+            account_id: account_id.clone(),
+            sale_id: sale.sale_id,
+            allocation,
+            expires_at,
+            issued_at,
+        }
+        .try_to_vec()
+        .unwrap();
+        SaleVoucher {
+            allocation: allocation.into(),
+            expires_at: expires_at.into(),
+            issued_at: issued_at.into(),
+            signature: keypair.sign(&message).to_bytes().to_vec().into(),
+        }
+    };
+    let voucher = |account_id: &AccountId, allocation: Balance, expires_at: Timestamp| {
+        sign_voucher(SKYWARD_ID, account_id, allocation, expires_at, current_time)
+    };
+    let deposit = |voucher: Option<SaleVoucher>| {
+        bob.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                voucher,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("0.01"),
+        )
+    };
+
+    // No voucher.
+    assert!(!deposit(None).is_ok());
+    // Expired voucher.
+    assert!(!deposit(Some(voucher(&bob.account_id, to_yocto("3"), current_time))).is_ok());
+    // Voucher for another account.
+    assert!(!deposit(Some(voucher(
+        &alice.account_id,
+        to_yocto("3"),
+        current_time + to_nano(WEEK)
+    )))
+    .is_ok());
+    // Tampered allocation.
+    let mut tampered_voucher =
+        voucher(&bob.account_id, to_yocto("3"), current_time + to_nano(WEEK));
+    tampered_voucher.allocation = to_yocto("5").into();
+    assert!(!deposit(Some(tampered_voucher)).is_ok());
+    // Voucher for another Skyward contract.
+    assert!(!deposit(Some(sign_voucher(
+        "staging.skyward.near",
+        &bob.account_id,
+        to_yocto("3"),
+        current_time + to_nano(WEEK),
+        current_time
+    )))
+    .is_ok());
+
+    // Only 3 out of 4 are accepted.
+    let valid_voucher = || voucher(&bob.account_id, to_yocto("3"), current_time + to_nano(WEEK));
+    deposit(Some(valid_voucher())).assert_success();
+    // The voucher can't be used again, and neither can older ones.
+    assert!(!deposit(Some(valid_voucher())).is_ok());
+    assert!(!deposit(Some(sign_voucher(
+        SKYWARD_ID,
+        &bob.account_id,
+        to_yocto("10"),
+        current_time + to_nano(WEEK),
+        current_time - 1
+    )))
+    .is_ok());
+    // A newer voucher raises the allocation.
+    deposit(Some(sign_voucher(
+        SKYWARD_ID,
+        &bob.account_id,
+        to_yocto("4"),
+        current_time + to_nano(WEEK),
+        current_time + 1,
+    )))
+    .assert_success();
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("6")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+}
+
+#[test]
+fn test_permissions_recheck_and_force_exit() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.permissions_contract_id = Some(e.permissions_contract.valid_account_id());
+    sale_input.recheck_permissions = Some(true);
+    sale_input.max_in_per_account = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    let permissions_call = |method: &str| {
+        // Approvals pay for their storage.
+        let deposit = if method == "approve" {
+            to_yocto("0.01")
+        } else {
+            0
+        };
+        e.skyward_dao
+            .call(
+                e.permissions_contract.account_id.clone(),
+                method,
+                &json!({ "account_id": bob.valid_account_id() })
+                    .to_string()
+                    .into_bytes(),
+                BASE_GAS,
+                deposit,
+            )
+            .assert_success();
+    };
+    let deposit = || -> bool {
+        bob.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("2").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+        .unwrap_json()
+    };
+    let force_exit = || {
+        alice.function_call(
+            e.skyward
+                .contract
+                .sale_force_exit(sale.sale_id, bob.valid_account_id()),
+            TON_OF_GAS,
+            1,
+        )
+    };
+
+    permissions_call("approve");
+    assert!(deposit());
+    assert!(deposit());
+
+    // Bob is still approved.
+    assert!(!force_exit().is_ok());
+
+    permissions_call("reject");
+    // Rejected accounts can't top up.
+    assert!(!deposit());
+
+    force_exit().assert_success();
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("10")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+    // The subscription is kept without shares, so the deposited amount is still tracked.
+    let subscription = e
+        .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+        .subscription
+        .unwrap();
+    assert_eq!(subscription.shares.0, 0);
+    assert!(!deposit());
+
+    // Carol can't move shares into the subscription without shares either.
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "approve",
+            &json!({ "account_id": carol.valid_account_id() })
+                .to_string()
+                .into_bytes(),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    let result: bool = carol
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("2").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("0.01"),
+        )
+        .unwrap_json();
+    assert!(result);
+    assert!(!carol
+        .function_call(
+            e.skyward
+                .contract
+                .subscription_transfer(sale.sale_id, bob.valid_account_id(), None),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .is_ok());
+
+    // Approved again, but only 1 more fits under the account maximum.
+    permissions_call("approve");
+    assert!(deposit());
+    assert_eq!(
+        e.balances_of(bob)[0],
+        (e.w_near.account_id.clone(), to_yocto("9"))
+    );
+}
+
+#[test]
+fn test_permissions_recheck_distributes_once() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.permissions_contract_id = Some(e.permissions_contract.valid_account_id());
+    sale_input.recheck_permissions = Some(true);
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    e.skyward_dao
+        .call(
+            e.permissions_contract.account_id.clone(),
+            "approve",
+            &json!({ "account_id": bob.valid_account_id() })
+                .to_string()
+                .into_bytes(),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+    let w_near_balance = |balances: Vec<(AccountId, Balance)>| {
+        balances
+            .into_iter()
+            .find(|(token_account_id, _)| token_account_id == &e.w_near.account_id)
+            .map(|(_, balance)| balance)
+            .unwrap_or(0)
+    };
+    let initial_owner_balance = w_near_balance(e.balances_of(alice));
+    let initial_treasury_balance = w_near_balance(e.get_treasury_balances());
+    // The owner and the treasury can't receive more than the paid in tokens.
+    let assert_distributed_once = || {
+        let sale = e.get_sale(sale.sale_id, None);
+        let distributed = sale.in_token_paid.0 - sale.in_token_paid_unclaimed.0;
+        let owner_received = w_near_balance(e.balances_of(alice)) - initial_owner_balance;
+        let treasury_received =
+            w_near_balance(e.get_treasury_balances()) - initial_treasury_balance;
+        assert!(owner_received > 0);
+        assert!(owner_received + treasury_received <= distributed);
+    };
+
+    let deposit = || {
+        let result: bool = bob
+            .function_call(
+                e.skyward.contract.sale_deposit_in_token(
+                    sale.sale_id,
+                    to_yocto("1").into(),
+                    None,
+                    None,
+                    None,
+                    None,
+                ),
+                TON_OF_GAS,
+                to_yocto("0.01"),
+            )
+            .unwrap_json();
+        assert!(result);
+    };
+    let deposit_on_transfer = || {
+        bob.call(
+            e.w_near.account_id.clone(),
+            "ft_transfer_call",
+            &json!({
+                "receiver_id": e.skyward.user_account.valid_account_id(),
+                "amount": U128::from(to_yocto("1")),
+                "msg": json!({ "SaleDeposit": { "sale_id": sale.sale_id } }).to_string(),
+            })
+            .to_string()
+            .into_bytes(),
+            TON_OF_GAS,
+            1,
+        )
+        .assert_success();
+    };
+
+    deposit();
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+    deposit();
+    deposit();
+    assert_distributed_once();
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp =
+        sale.start_time.0 + sale.duration.0 * 3 / 4;
+    deposit_on_transfer();
+    deposit_on_transfer();
+    assert_distributed_once();
+}
+
+#[test]
+fn test_permissions_force_exit_with_allocations() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.permissions_contract_id = Some(e.permissions_contract.valid_account_id());
+    sale_input.permissions_with_allocations = Some(true);
+    sale_input.min_in_token_paid = Some(to_yocto("1").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    let permissions_call = |method: &str, args: Value, deposit: Balance| {
+        e.skyward_dao
+            .call(
+                e.permissions_contract.account_id.clone(),
+                method,
+                &args.to_string().into_bytes(),
+                BASE_GAS,
+                deposit,
+            )
+            .assert_success();
+    };
+    let deposit = || {
+        bob.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("2").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("1"),
+        )
+    };
+    let force_exit = || {
+        alice.function_call(
+            e.skyward
+                .contract
+                .sale_force_exit(sale.sale_id, bob.valid_account_id()),
+            TON_OF_GAS,
+            1,
+        )
+    };
+
+    permissions_call(
+        "approve",
+        json!({ "account_id": bob.valid_account_id() }),
+        to_yocto("0.01"),
+    );
+    permissions_call(
+        "set_allocation",
+        json!({
+            "account_id": bob.valid_account_id(),
+            "sale_id": sale.sale_id,
+            "allocation": U128::from(to_yocto("3")),
+        }),
+        to_yocto("0.01"),
+    );
+    let result: bool = deposit().unwrap_json();
+    assert!(result);
+
+    // Bob still has an allocation.
+    assert!(!force_exit().is_ok());
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+
+    permissions_call("reject", json!({ "account_id": bob.valid_account_id() }), 0);
+    force_exit().assert_success();
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("9")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+
+    // The subscription keeps the locked out tokens, but the next deposit is checked again.
+    let subscription = e
+        .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+        .subscription
+        .unwrap();
+    assert_eq!(subscription.shares.0, 0);
+    let result: bool = deposit().unwrap_json();
+    assert!(!result);
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("9")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+}
+
+#[test]
+fn test_sale_deposit_on_transfer() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let charlie = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.max_in_token = Some(to_yocto("5").into());
+    let sale = e.sale_create_from_input(alice, sale_input);
+
+    let sale_deposit = |user: &UserAccount, amount: Balance| {
+        user.call(
+            e.w_near.account_id.clone(),
+            "ft_transfer_call",
+            &json!({
+                "receiver_id": e.skyward.user_account.valid_account_id(),
+                "amount": U128::from(amount),
+                "msg": json!({ "SaleDeposit": { "sale_id": sale.sale_id } }).to_string(),
+            })
+            .to_string()
+            .into_bytes(),
+            TON_OF_GAS,
+            1,
+        )
+        .assert_success();
+    };
+
+    sale_deposit(bob, to_yocto("4"));
+    assert_eq!(e.get_token_balance(&e.w_near, bob), to_yocto("6"));
+    // The storage is paid from the wNEAR balance.
+    let bob_balance = e.balances_of(bob)[0].1;
+    assert!(to_yocto("9.99") < bob_balance && bob_balance < to_yocto("10"));
+
+    // Only 1 out of 4 is accepted, the rest is refunded.
+    sale_deposit(charlie, to_yocto("4"));
+    let charlie_balance = e.get_token_balance(&e.w_near, charlie);
+    assert!(to_yocto("8.99") < charlie_balance && charlie_balance < to_yocto("9"));
+
+    let sale = e.get_sale(sale.sale_id, None);
+    assert_eq!(sale.in_token_remaining.0, to_yocto("5"));
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    // The sale has ended, so everything is refunded.
+    sale_deposit(bob, to_yocto("1"));
+    assert_eq!(e.get_token_balance(&e.w_near, bob), to_yocto("6"));
+}
+
+#[test]
+fn test_sale_create_on_transfer() {
+    let e = Env::init_with_schedule(1, vec![]);
+    let alice = e.users.get(0).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    // The listing fee is paid in wNEAR.
+    e.register_and_deposit(&alice, &e.w_near, to_yocto("5"));
+
+    let sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("4000"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    alice
+        .call(
+            token1.account_id.clone(),
+            "ft_transfer_call",
+            &json!({
+                "receiver_id": e.skyward.user_account.valid_account_id(),
+                "amount": U128::from(to_yocto("4000")),
+                "msg": json!({ "SaleCreate": { "sale": sale_input } }).to_string(),
+            })
+            .to_string()
+            .into_bytes(),
+            TON_OF_GAS,
+            1,
+        )
+        .assert_success();
+
+    let sale = e.get_sale(0, None);
+    assert_eq!(sale.owner_id, alice.account_id);
+    assert_eq!(sale.out_tokens[0].remaining.0, to_yocto("4000"));
+
+    let balances = e.balances_of(alice);
+    assert_eq!(balances[0].0, e.w_near.account_id);
+    assert!(to_yocto("4.99") < balances[0].1 && balances[0].1 < to_yocto("5"));
+    assert_eq!(balances[1], (token1.account_id.clone(), 0));
+}
+
+#[test]
+fn test_sale_deposit_near() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_near(sale.sale_id, None),
+        TON_OF_GAS,
+        to_yocto("3"),
+    )
+    .assert_success();
+
+    let sale = e.get_sale(sale.sale_id, None);
+    assert_eq!(sale.in_token_remaining.0, to_yocto("3"));
+    // The storage is paid from the wNEAR balance.
+    let w_near_balance = e.balances_of(bob)[0].1;
+    assert!(to_yocto("9.99") < w_near_balance && w_near_balance < to_yocto("10"));
+
+    let initial_balance = bob.account().unwrap().amount;
+    bob.function_call(
+        e.skyward.contract.withdraw_near(Some(to_yocto("2").into())),
+        TON_OF_GAS,
+        0,
+    )
+    .assert_success();
+    assert_eq!(e.balances_of(bob)[0].1, w_near_balance - to_yocto("2"));
+    let balance_gained = bob.account().unwrap().amount - initial_balance;
+    assert!(to_yocto("1.99") < balance_gained && balance_gained < to_yocto("2"));
+}
+
+#[test]
+fn test_claim_all() {
+    let e = Env::init_with_schedule(2, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+    storage_deposit(&e.near, TOKEN1_ID, &bob.account_id);
+
+    let sale_amount = to_yocto("2000");
+    let sales = vec![
+        e.sale_create(alice, &[(&token1, sale_amount)]),
+        e.sale_create(alice, &[(&token1, sale_amount)]),
+    ];
+    for sale in &sales {
+        bob.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    }
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp =
+        sales[1].start_time.0 + sales[1].duration.0;
+
+    let claimed: Vec<(AccountId, WrappedBalance)> = bob
+        .function_call(
+            e.skyward
+                .contract
+                .claim_all(sales.iter().map(|sale| sale.sale_id).collect(), true),
+            TON_OF_GAS,
+            0,
+        )
+        .unwrap_json();
+    assert_eq!(
+        claimed,
+        vec![(
+            token1.account_id.clone(),
+            (sale_amount * 99 / 100 * 2).into()
+        )]
+    );
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("2")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+    assert_eq!(
+        e.get_token_balance(&token1, bob),
+        sale_amount * 99 / 100 * 2
+    );
+}
+
+#[test]
+fn test_withdraw_token_to_receiver() {
+    let e = Env::init(2);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    let initial_balance = e.get_token_balance(&e.w_near, bob);
+    alice
+        .function_call(
+            e.skyward.contract.withdraw_token_to(
+                bob.valid_account_id(),
+                e.w_near.valid_account_id(),
+                Some(to_yocto("1").into()),
+            ),
+            TON_OF_GAS,
+            1,
+        )
+        .assert_success();
+    assert_eq!(
+        e.get_token_balance(&e.w_near, bob),
+        initial_balance + to_yocto("1")
+    );
+    assert_eq!(
+        e.balances_of(alice),
+        vec![(e.w_near.account_id.clone(), to_yocto("9"))]
+    );
+
+    // The transfer to Skyward itself fails, so the whole amount is returned.
+    let used_amount: WrappedBalance = alice
+        .function_call(
+            e.skyward.contract.withdraw_token_call(
+                e.skyward.user_account.valid_account_id(),
+                e.w_near.valid_account_id(),
+                Some(to_yocto("2").into()),
+                "\"AccountDeposit\"".to_string(),
+            ),
+            TON_OF_GAS,
+            1,
+        )
+        .unwrap_json();
+    assert_eq!(used_amount.0, 0);
+    assert_eq!(
+        e.balances_of(alice),
+        vec![(e.w_near.account_id.clone(), to_yocto("9"))]
+    );
+}
+
+#[test]
+fn test_storage_management() {
+    let e = Env::init(0);
+    let alice = e.root.create_user(accounts(0).into(), to_yocto("100"));
+    let bob = e.root.create_user(accounts(1).into(), to_yocto("100"));
+
+    let storage_balance_of = |user: &UserAccount| -> Value {
+        e.near
+            .view(
+                SKYWARD_ID.to_string(),
+                "storage_balance_of",
+                &json!({ "account_id": user.valid_account_id() })
+                    .to_string()
+                    .into_bytes(),
+            )
+            .unwrap_json()
+    };
+    assert_eq!(storage_balance_of(&alice), Value::Null);
+
+    alice
+        .call(
+            SKYWARD_ID.to_string(),
+            "storage_deposit",
+            &json!({}).to_string().into_bytes(),
+            BASE_GAS,
+            to_yocto("1"),
+        )
+        .assert_success();
+    let storage_balance: WrappedBalance =
+        serde_json::from_value(storage_balance_of(&alice)["available"].clone()).unwrap();
+    assert!(to_yocto("0.99") < storage_balance.0 && storage_balance.0 < to_yocto("1"));
+    assert_eq!(
+        storage_balance_of(&alice)["total"],
+        json!(to_yocto("1").to_string())
+    );
+
+    // Registering tokens with 1 yocto is paid from the storage balance.
+    alice
+        .function_call(
+            e.skyward
+                .contract
+                .register_token(None, e.w_near.valid_account_id()),
+            BASE_GAS,
+            1,
+        )
+        .assert_success();
+    let new_storage_balance: WrappedBalance =
+        serde_json::from_value(storage_balance_of(&alice)["available"].clone()).unwrap();
+    assert!(new_storage_balance.0 < storage_balance.0);
+
+    let initial_balance = alice.account().unwrap().amount;
+    alice
+        .call(
+            SKYWARD_ID.to_string(),
+            "storage_withdraw",
+            &json!({}).to_string().into_bytes(),
+            BASE_GAS,
+            1,
+        )
+        .assert_success();
+    assert_eq!(storage_balance_of(&alice)["available"], json!("0"));
+    assert_eq!(
+        storage_balance_of(&alice)["total"],
+        json!((to_yocto("1") - new_storage_balance.0).to_string())
+    );
+    let balance_gained = alice.account().unwrap().amount - initial_balance;
+    assert!(balance_gained > new_storage_balance.0 - to_yocto("0.01"));
+
+    // Registration only refunds the rest of the deposit, and the empty account can unregister.
+    bob.call(
+        SKYWARD_ID.to_string(),
+        "storage_deposit",
+        &json!({ "registration_only": true })
+            .to_string()
+            .into_bytes(),
+        BASE_GAS,
+        to_yocto("1"),
+    )
+    .assert_success();
+    assert_eq!(storage_balance_of(&bob)["available"], json!("0"));
+    let bounds: Value = e
+        .near
+        .view(
+            SKYWARD_ID.to_string(),
+            "storage_balance_bounds",
+            &json!({}).to_string().into_bytes(),
+        )
+        .unwrap_json();
+    // Only the registration is paid.
+    let total: WrappedBalance =
+        serde_json::from_value(storage_balance_of(&bob)["total"].clone()).unwrap();
+    let min: WrappedBalance = serde_json::from_value(bounds["min"].clone()).unwrap();
+    assert!(0 < total.0 && total.0 <= min.0);
+    let unregistered: bool = bob
+        .call(
+            SKYWARD_ID.to_string(),
+            "storage_unregister",
+            &json!({}).to_string().into_bytes(),
+            BASE_GAS,
+            1,
+        )
+        .unwrap_json();
+    assert!(unregistered);
+    assert_eq!(storage_balance_of(&bob), Value::Null);
+}
+
+#[test]
+fn test_unregister_tokens_and_close_account() {
+    let e = Env::init_with_schedule(1, vec![]);
+    let alice = e.users.get(0).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    let donate = |token: &UserAccount, amount: Balance| {
+        alice
+            .function_call(
+                e.skyward
+                    .contract
+                    .donate_token_to_treasury(token.valid_account_id(), amount.into()),
+                BASE_GAS,
+                1,
+            )
+            .assert_success();
+    };
+    let unregister_tokens = |tokens: &[&UserAccount]| {
+        alice.function_call(
+            e.skyward.contract.unregister_tokens(
+                tokens
+                    .iter()
+                    .map(|token| token.valid_account_id())
+                    .collect(),
+            ),
+            BASE_GAS,
+            1,
+        )
+    };
+    let close_account = || alice.function_call(e.skyward.contract.close_account(), TON_OF_GAS, 1);
+
+    // The wNEAR balance is not empty.
+    assert!(!unregister_tokens(&[&e.w_near]).is_ok());
+    donate(&e.w_near, to_yocto("10"));
+    donate(&token1, to_yocto("6000"));
+    // Both tokens are used by the unfinished sale.
+    assert!(!unregister_tokens(&[&e.w_near]).is_ok());
+    assert!(!close_account().is_ok());
+
+    // The remaining out tokens are returned to the owner at the end of the sale.
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+    alice
+        .function_call(
+            e.skyward
+                .contract
+                .sale_distribute_unclaimed_tokens(sale.sale_id),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+    assert!(!close_account().is_ok());
+    assert_eq!(
+        e.balances_of(alice),
+        vec![
+            (e.w_near.account_id.clone(), 0),
+            (token1.account_id.clone(), to_yocto("4000")),
+        ]
+    );
+
+    unregister_tokens(&[&e.w_near]).assert_success();
+    assert_eq!(
+        e.balances_of(alice),
+        vec![(token1.account_id.clone(), to_yocto("4000"))]
+    );
+
+    donate(&token1, to_yocto("4000"));
+    let initial_balance = alice.account().unwrap().amount;
+    close_account().assert_success();
+    assert!(alice.account().unwrap().amount > initial_balance);
+    assert!(e.balances_of(alice).is_empty());
+}
+
+#[test]
+fn test_transfer() {
+    let e = Env::init(2);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+
+    alice
+        .function_call(
+            e.skyward.contract.transfer(
+                bob.valid_account_id(),
+                e.w_near.valid_account_id(),
+                to_yocto("3").into(),
+                Some("OTC settlement".to_string()),
+            ),
+            BASE_GAS,
+            1,
+        )
+        .assert_success();
+    assert_eq!(
+        e.balances_of(alice),
+        vec![(e.w_near.account_id.clone(), to_yocto("7"))]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![(e.w_near.account_id.clone(), to_yocto("13"))]
+    );
+
+    // Can't transfer more than the balance.
+    assert!(!alice
+        .function_call(
+            e.skyward.contract.transfer(
+                bob.valid_account_id(),
+                e.w_near.valid_account_id(),
+                to_yocto("8").into(),
+                None,
+            ),
+            BASE_GAS,
+            1,
+        )
+        .is_ok());
+
+    // Bob is not a contract, so the whole amount is returned.
+    let used_amount: WrappedBalance = alice
+        .function_call(
+            e.skyward.contract.transfer_call(
+                bob.valid_account_id(),
+                e.w_near.valid_account_id(),
+                to_yocto("2").into(),
+                None,
+                "".to_string(),
+            ),
+            TON_OF_GAS,
+            1,
+        )
+        .unwrap_json();
+    assert_eq!(used_amount.0, 0);
+    assert_eq!(
+        e.balances_of(alice),
+        vec![(e.w_near.account_id.clone(), to_yocto("7"))]
+    );
+    assert_eq!(
+        e.balances_of(bob),
+        vec![(e.w_near.account_id.clone(), to_yocto("13"))]
+    );
+}
+
+#[test]
+fn test_subscription_transfer() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale_amount = to_yocto("4000");
+    let sale = e.sale_create(alice, &[(&token1, sale_amount)]);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            Some(alice.valid_account_id()),
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+    let bob_shares = e
+        .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+        .subscription
+        .unwrap()
+        .shares
+        .0;
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0 / 2;
+
+    bob.function_call(
+        e.skyward.contract.subscription_transfer(
+            sale.sale_id,
+            carol.valid_account_id(),
+            Some((bob_shares / 2).into()),
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    // The out tokens bought before the transfer stay with Bob.
+    let distributed_amount = sale_amount / 2 * 99 / 100;
+    assert_eq!(
+        e.balances_of(bob)[1],
+        (token1.account_id.clone(), distributed_amount)
+    );
+
+    for user in &[bob, carol] {
+        let subscription = e
+            .get_sale(sale.sale_id, Some(user.valid_account_id()))
+            .subscription
+            .unwrap();
+        assert_eq!(subscription.shares.0, bob_shares / 2);
+        assert_eq!(subscription.remaining_in_balance.0, to_yocto("1"));
+        assert_eq!(subscription.spent_in_balance.0, to_yocto("1"));
+        // The referral moves with the shares.
+        assert_eq!(subscription.referral_id, Some(alice.account_id.clone()));
+    }
+
+    e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+
+    for user in &[bob, carol] {
+        user.function_call(
+            e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+            BASE_GAS,
+            0,
+        )
+        .assert_success();
+    }
+    let bob_amount = e.balances_of(bob)[1].1 - distributed_amount;
+    let carol_amount = e.balances_of(carol)[1].1;
+    assert_eq!(bob_amount, carol_amount);
+    assert!(distributed_amount - bob_amount - carol_amount < 10);
+}
+
+#[test]
+fn test_subscription_transfer_min_in_token_paid() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+
+    let sale_amount = to_yocto("4000");
+    // Bob deposits 4 and transfers half of the shares to Carol in the middle of the sale.
+    let run_sale = |min_in_token_paid: Balance| -> SaleOutput {
+        let mut sale_input = e.sale_input(
+            alice,
+            &[(&token1, sale_amount)],
+            to_nano(WEEK) + BLOCK_DURATION * 15,
+            BLOCK_DURATION * 60,
+        );
+        sale_input.min_in_token_paid = Some(min_in_token_paid.into());
+        let sale = e.sale_create_from_input(alice, sale_input);
+        bob.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("4").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+        e.near.borrow_runtime_mut().cur_block.block_timestamp =
+            sale.start_time.0 + sale.duration.0 / 2;
+        let bob_shares = e
+            .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+            .subscription
+            .unwrap()
+            .shares
+            .0;
+        bob.function_call(
+            e.skyward.contract.subscription_transfer(
+                sale.sale_id,
+                carol.valid_account_id(),
+                Some((bob_shares / 2).into()),
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+
+        e.near.borrow_runtime_mut().cur_block.block_timestamp = sale.start_time.0 + sale.duration.0;
+        for user in &[bob, carol] {
+            user.function_call(
+                e.skyward.contract.sale_claim_out_tokens(sale.sale_id, None),
+                BASE_GAS,
+                0,
+            )
+            .assert_success();
+        }
+        e.get_sale(sale.sale_id, None)
+    };
+
+    // The locked out tokens bought before the transfer are split with the shares.
+    let sale = run_sale(to_yocto("3"));
+    assert!(!sale.failed);
+    let bob_amount = e.balances_of(bob)[1].1;
+    let carol_amount = e.balances_of(carol)[1].1;
+    assert_eq!(bob_amount, carol_amount);
+    assert!(sale_amount * 99 / 100 - bob_amount - carol_amount < 10);
+    assert_eq!(e.balances_of(bob)[0].1, to_yocto("6"));
+
+    // Both accounts get back the in tokens they spent.
+    let sale = run_sale(to_yocto("5"));
+    assert!(sale.failed);
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("4")),
+            (token1.account_id.clone(), bob_amount),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(carol),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("12")),
+            (token1.account_id.clone(), carol_amount),
+        ]
+    );
+}
+
+#[test]
+fn test_subscription_nft() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("4").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    let total_supply: U128 = e
+        .near
+        .view_method_call(e.skyward.contract.nft_total_supply())
+        .unwrap_json();
+    assert_eq!(total_supply.0, 1);
+    let tokens: Value = e
+        .near
+        .view_method_call(e.skyward.contract.nft_tokens_for_owner(
+            bob.valid_account_id(),
+            None,
+            None,
+        ))
+        .unwrap_json();
+    let token_id = "0".to_string();
+    assert_eq!(tokens[0]["token_id"], json!(token_id));
+    assert_eq!(tokens[0]["owner_id"], json!(bob.account_id));
+    let extra: Value =
+        serde_json::from_str(tokens[0]["metadata"]["extra"].as_str().unwrap()).unwrap();
+    assert_eq!(extra["sale_id"], json!(sale.sale_id));
+    assert_eq!(extra["shares"], json!(to_yocto("4").to_string()));
+    assert_eq!(extra["transferable"], json!(true));
+    let supply: U128 = e
+        .near
+        .view_method_call(
+            e.skyward
+                .contract
+                .nft_supply_for_owner(bob.valid_account_id()),
+        )
+        .unwrap_json();
+    assert_eq!(supply.0, 1);
+
+    // Only the owner or an approved account can transfer the token.
+    let transfer_to_carol = |approval_id: Option<u64>| {
+        carol.function_call(
+            e.skyward.contract.nft_transfer(
+                carol.valid_account_id(),
+                token_id.clone(),
+                approval_id,
+                None,
+            ),
+            TON_OF_GAS,
+            to_yocto("0.01"),
+        )
+    };
+    assert!(!transfer_to_carol(None).is_ok());
+
+    bob.function_call(
+        e.skyward
+            .contract
+            .nft_approve(token_id.clone(), carol.valid_account_id(), None),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+    let is_approved: bool = e
+        .near
+        .view_method_call(e.skyward.contract.nft_is_approved(
+            token_id.clone(),
+            carol.valid_account_id(),
+            Some(0),
+        ))
+        .unwrap_json();
+    assert!(is_approved);
+    // Wrong approval ID.
+    assert!(!transfer_to_carol(Some(1)).is_ok());
+    transfer_to_carol(Some(0)).assert_success();
+
+    // The token keeps its ID, and the approvals are cleared.
+    let token: Value = e
+        .near
+        .view_method_call(e.skyward.contract.nft_token(token_id.clone()))
+        .unwrap_json();
+    assert_eq!(token["owner_id"], json!(carol.account_id));
+    assert_eq!(token["approved_account_ids"], json!({}));
+    let tokens: Value = e
+        .near
+        .view_method_call(e.skyward.contract.nft_tokens(None, None))
+        .unwrap_json();
+    assert_eq!(tokens.as_array().unwrap().len(), 1);
+
+    let subscription = e
+        .get_sale(sale.sale_id, Some(carol.valid_account_id()))
+        .subscription
+        .unwrap();
+    assert_eq!(subscription.remaining_in_balance.0, to_yocto("4"));
+    assert!(e
+        .get_sale(sale.sale_id, Some(bob.valid_account_id()))
+        .subscription
+        .is_none());
+
+    // A new subscription gets a new token.
+    bob.function_call(
+        e.skyward.contract.sale_deposit_in_token(
+            sale.sale_id,
+            to_yocto("2").into(),
+            None,
+            None,
+            None,
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+    let tokens: Value = e
+        .near
+        .view_method_call(e.skyward.contract.nft_tokens_for_owner(
+            bob.valid_account_id(),
+            None,
+            None,
+        ))
+        .unwrap_json();
+    assert_eq!(tokens[0]["token_id"], json!("1"));
+
+    // The receiver can't have a subscription to the sale already.
+    assert!(!carol
+        .function_call(
+            e.skyward
+                .contract
+                .nft_transfer(bob.valid_account_id(), token_id.clone(), None, None),
+            TON_OF_GAS,
+            1,
+        )
+        .is_ok());
+    assert_eq!(
+        e.get_sale(sale.sale_id, Some(bob.valid_account_id()))
+            .subscription
+            .unwrap()
+            .remaining_in_balance
+            .0,
+        to_yocto("2")
+    );
+}
+
+#[test]
+fn test_operators() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    bob.function_call(
+        e.skyward.contract.approve_operator(
+            carol.valid_account_id(),
+            Some(vec![(e.w_near.valid_account_id(), to_yocto("3").into())]),
+            None,
+        ),
+        BASE_GAS,
+        to_yocto("0.01"),
+    )
+    .assert_success();
+
+    let deposit_for_bob = |amount: Balance| {
+        carol.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                amount.into(),
+                None,
+                None,
+                None,
+                Some(bob.valid_account_id()),
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+    };
+    deposit_for_bob(to_yocto("2")).assert_success();
+    // Over the spending limit.
+    assert!(!deposit_for_bob(to_yocto("2")).is_ok());
+
+    assert_eq!(
+        e.balances_of(bob),
+        vec![
+            (e.w_near.account_id.clone(), to_yocto("8")),
+            (token1.account_id.clone(), 0),
+        ]
+    );
+    assert_eq!(
+        e.balances_of(carol),
+        vec![(e.w_near.account_id.clone(), to_yocto("10"))]
+    );
+    let operators: Vec<OperatorOutput> = e
+        .near
+        .view_method_call(e.skyward.contract.get_operators(bob.valid_account_id()))
+        .unwrap_json();
+    assert_eq!(
+        operators,
+        vec![OperatorOutput {
+            account_id: carol.account_id.clone(),
+            spending_limits: Some(vec![(e.w_near.account_id.clone(), to_yocto("1").into())]),
+            expires_at: None,
+        }]
+    );
+
+    carol
+        .function_call(
+            e.skyward.contract.sale_withdraw_in_token(
+                sale.sale_id,
+                None,
+                Some(bob.valid_account_id()),
+            ),
+            BASE_GAS,
+            1,
+        )
+        .assert_success();
+    assert_eq!(
+        e.balances_of(bob)[0],
+        (e.w_near.account_id.clone(), to_yocto("10"))
+    );
+
+    // Only the amount accepted by the sale counts against the spending limit, even if more is
+    // requested.
+    let mut sale_input = e.sale_input(
+        alice,
+        &[(&token1, to_yocto("3600"))],
+        to_nano(WEEK) + BLOCK_DURATION * 15,
+        BLOCK_DURATION * 60,
+    );
+    sale_input.max_in_per_account = Some(to_yocto("0.4").into());
+    let capped_sale = e.sale_create_from_input(alice, sale_input);
+    carol
+        .function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                capped_sale.sale_id,
+                to_yocto("2").into(),
+                None,
+                None,
+                None,
+                Some(bob.valid_account_id()),
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    assert_eq!(
+        e.balances_of(bob)[0],
+        (e.w_near.account_id.clone(), to_yocto("9.6"))
+    );
+    let operators: Vec<OperatorOutput> = e
+        .near
+        .view_method_call(e.skyward.contract.get_operators(bob.valid_account_id()))
+        .unwrap_json();
+    assert_eq!(
+        operators[0].spending_limits,
+        Some(vec![(e.w_near.account_id.clone(), to_yocto("0.6").into())])
+    );
+
+    bob.function_call(
+        e.skyward.contract.revoke_operator(carol.valid_account_id()),
+        BASE_GAS,
+        1,
+    )
+    .assert_success();
+    assert!(!deposit_for_bob(to_yocto("1")).is_ok());
+    // Alice is not an operator of Bob.
+    assert!(!alice
+        .function_call(
+            e.skyward
+                .contract
+                .sale_claim_out_tokens(sale.sale_id, Some(bob.valid_account_id())),
+            BASE_GAS,
+            0,
+        )
+        .is_ok());
+}
+
+#[test]
+fn test_limit_orders() {
+    let e = Env::init_with_schedule(3, vec![]);
+    let alice = e.users.get(0).unwrap();
+    let bob = e.users.get(1).unwrap();
+    let carol = e.users.get(2).unwrap();
+
+    let token1 = e.deploy_ft(&alice.account_id, TOKEN1_ID);
+    e.register_and_deposit(&alice, &token1, to_yocto("10000"));
+    let sale = e.sale_create(alice, &[(&token1, to_yocto("4000"))]);
+
+    for user in &[bob, carol] {
+        user.function_call(
+            e.skyward.contract.sale_deposit_in_token(
+                sale.sale_id,
+                to_yocto("2").into(),
+                None,
+                None,
+                None,
+                None,
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+        .assert_success();
+    }
+
+    let set_max_price = |user: &UserAccount, in_amount: Balance, out_amount: Balance| {
+        user.function_call(
+            e.skyward.contract.sale_set_max_price(
+                sale.sale_id,
+                Some(SalePrice {
+                    in_amount: in_amount.into(),
+                    out_amount: out_amount.into(),
+                }),
+            ),
+            BASE_GAS,
+            to_yocto("0.01"),
+        )
+    };
+    // Bob accepts a price above the current one, Carol doesn't.
+    set_max_price(bob, to_yocto("1"), to_yocto("100")).assert_success();
+    set_max_price(carol, to_yocto("1"), to_yocto("10000")).assert_success();
+    // Alice has no subscription.
+    assert!(!set_max_price(alice, to_yocto("1"), to_yocto("100")).is_ok());
+    // Zero amounts are invalid.
+    assert!(!set_max_price(bob, to_yocto("1"), 0).is_ok());
+
+    assert_eq!(
+        e.get_sale(sale.sale_id, Some(bob.valid_account_id()))
+            .subscription
+            .unwrap()
+            .max_price,
+        Some(SalePrice {
+            in_amount: to_yocto("1").into(),
+            out_amount: to_yocto("100").into(),
+        })
+    );
+
+    let exited_account_ids: Vec<AccountId> = alice
+        .function_call(
+            e.skyward.contract.sale_enforce_limits(
+                sale.sale_id,
+                vec![bob.valid_account_id(), carol.valid_account_id()],
+            ),
+            TON_OF_GAS,
+            0,
+        )
+        .unwrap_json();
+    assert_eq!(exited_account_ids, vec![carol.account_id.clone()]);
+
+    let bobs_sale = e.get_sale(sale.sale_id, Some(bob.valid_account_id()));
+    assert_eq!(bobs_sale.in_token_remaining.0, to_yocto("2"));
+    assert_eq!(
+        bobs_sale.subscription.unwrap().remaining_in_balance.0,
+        to_yocto("2")
+    );
+    assert!(e
+        .get_sale(sale.sale_id, Some(carol.valid_account_id()))
+        .subscription
+        .map(|subscription| subscription.remaining_in_balance.0 == 0)
+        .unwrap_or(true));
+    // The storage for the max price was paid from wNEAR.
+    assert!(e.balances_of(carol)[0].1 > to_yocto("9.99"));
+}